@@ -0,0 +1,139 @@
+use rand;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use sha1::Sha1;
+use sha2::{Sha256, Digest};
+
+use chat::now_millis;
+use http::Request;
+
+/// Cookie checked for a session token when none is given in the query
+/// string or `Authorization` header.
+static TOKEN_COOKIE: &'static str = "shitchat_token";
+
+static SHA256_BLOCK_LEN: usize = 64;
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+/// HMAC-SHA256, per RFC 2104.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut key = if key.len() > SHA256_BLOCK_LEN {
+        sha256(key)
+    } else {
+        key.to_vec()
+    };
+    key.resize(SHA256_BLOCK_LEN, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend(message.iter());
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(sha256(&inner[..]).iter());
+    sha256(&outer[..])
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn decode_json_part(part: &str) -> Result<Json, String> {
+    let bytes = try!(part.from_base64().map_err(|e| e.to_string()));
+    let text = try!(String::from_utf8(bytes).map_err(|e| e.to_string()));
+    Json::from_str(&text[..]).map_err(|e| e.to_string())
+}
+
+/// Verifies an HS256 JWT and returns its subject.
+///
+/// The token must carry a `sub` string and an `exp` time in seconds since
+/// the epoch, and is also rejected before any `nbf` time.
+pub fn verify_token(token: &str, secret: &str) -> Result<String, String> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(String::from("malformed token"));
+    }
+
+    let header = try!(decode_json_part(parts[0]));
+    if header.find("alg").and_then(|a| a.as_string()) != Some("HS256") {
+        return Err(String::from("unsupported token algorithm"));
+    }
+
+    let signing_input = format!("{}.{}", parts[0], parts[1]);
+    let expected = hmac_sha256(secret.as_bytes(), signing_input.as_bytes());
+    let signature = try!(parts[2].from_base64().map_err(|e| e.to_string()));
+    if !constant_time_eq(&expected[..], &signature[..]) {
+        return Err(String::from("bad token signature"));
+    }
+
+    let claims = try!(decode_json_part(parts[1]));
+    let now = (now_millis() / 1000) as f64;
+    match claims.find("exp").and_then(|e| e.as_f64()) {
+        Some(exp) if exp > now => (),
+        Some(_) => return Err(String::from("token expired")),
+        None => return Err(String::from("token has no expiry")),
+    }
+    match claims.find("nbf").and_then(|n| n.as_f64()) {
+        Some(nbf) if nbf > now => {
+            return Err(String::from("token not yet valid"));
+        },
+        _ => (),
+    }
+    match claims.find("sub").and_then(|s| s.as_string()) {
+        Some(sub) if sub.len() > 0 => Ok(String::from(sub)),
+        _ => Err(String::from("token has no subject")),
+    }
+}
+
+/// Finds a session token in the `token` query parameter, an
+/// `Authorization: Bearer` header or the session cookie.
+fn find_token(request: &Request) -> Option<String> {
+    if let Some(token) = request.get_query_param("token") {
+        return Some(token);
+    }
+    if let Some(authorization) = request.get_header("Authorization") {
+        let mut frags = authorization.splitn(2, ' ');
+        if frags.next() == Some("Bearer") {
+            return frags.next().map(|t| String::from(t.trim()));
+        }
+    }
+    request.get_cookie(TOKEN_COOKIE)
+}
+
+/// Checks a WebSocket upgrade request against the configured shared secret.
+///
+/// Returns the authenticated identity, or None if authentication is not
+/// configured.
+pub fn authenticate(request: &Request) -> Result<Option<String>, String> {
+    let secret = match request.config.auth_secret {
+        Some(ref secret) => secret,
+        None => return Ok(None),
+    };
+    match find_token(request) {
+        Some(token) => verify_token(&token[..], &secret[..]).map(Some),
+        None => Err(String::from("no session token")),
+    }
+}
+
+/// Keeps client_ids to 53 bits, so they survive a round trip through a
+/// JavaScript number.
+static CLIENT_ID_MASK: u64 = 0x1F_FFFF_FFFF_FFFF;
+
+/// Derives a stable client_id from an authenticated identity.
+pub fn identity_client_id(identity: &str) -> i64 {
+    let mut sha = Sha1::new();
+    sha.update(identity.as_bytes());
+    let digest: Vec<u8> = sha.digest();
+    let id = digest.iter().take(8).fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    (id & CLIENT_ID_MASK) as i64
+}
+
+/// A client_id for a client without an identity.
+pub fn random_client_id() -> i64 {
+    (rand::random::<u64>() & CLIENT_ID_MASK) as i64
+}
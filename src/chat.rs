@@ -1,279 +1,2770 @@
-use std::net::{TcpStream};
+use std::net::Shutdown;
 use std::io::{Read, Write};
 use bufstream::BufStream;
-use std::sync::mpsc::{Sender, Receiver, channel};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, SyncSender, Receiver, TrySendError, channel,
+                      sync_channel};
+use std::sync::Arc;
 use std::thread;
-use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use rand;
+use bcrypt;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 
-use rustc_serialize::json;
 
+use auth;
+use commands::{self, Command, Commands, Invocation};
+use config::Config;
+use history::History;
 use http::Request;
+use storage::{self, Storage, RoomRecord, Account, Ban, MessageEdit,
+              StoredReaction};
+use protocol::Codec;
+use queue::{OutboundQueue, Outbound, CLOSE_NORMAL, CLOSE_POLICY_VIOLATION,
+            CLOSE_REPLACED};
+use ratelimit::RateLimiter;
+use reactions::{self, Reactions, Reaction, MessageReactions};
+use roomname;
+use stream::Stream;
+use typing::TypingTracker;
+use username;
 use ws;
 
-#[derive(Clone, Debug, RustcEncodable)]
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
 struct ClientIdUsername {
     client_id: i64,
     username: String,
 }
 
-#[derive(Clone, Debug, RustcEncodable)]
+/// Room every client is placed in on connect.
+pub static LOBBY: &'static str = "lobby";
+
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+pub struct RoomSummary {
+    name: String,
+    members: usize,
+}
+
+/// Replies to a message, as listed in a `History`.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+pub struct ThreadSummary {
+    parent_id: u64,
+    reply_count: usize,
+}
+
+/// The message couldn't be parsed as a `ClientMessage`.
+pub static ERR_BAD_REQUEST: &'static str = "bad_request";
+/// The message's `variant` isn't a known command.
+pub static ERR_UNKNOWN_COMMAND: &'static str = "unknown_command";
+/// The client is sending faster than the configured rate limit.
+pub static ERR_RATE_LIMITED: &'static str = "rate_limited";
+/// The client isn't a member of the room it addressed.
+pub static ERR_NOT_IN_ROOM: &'static str = "not_in_room";
+pub static ERR_RECIPIENT_UNAVAILABLE: &'static str = "recipient_unavailable";
+/// The requested username failed validation.
+pub static ERR_INVALID_USERNAME: &'static str = "invalid_username";
+pub static ERR_USERNAME_IN_USE: &'static str = "username_in_use";
+pub static ERR_USERNAME_BANNED: &'static str = "username_banned";
+/// The username belongs to a registered account; log in to use it.
+pub static ERR_USERNAME_RESERVED: &'static str = "username_reserved";
+pub static ERR_ACCOUNT_EXISTS: &'static str = "account_exists";
+pub static ERR_PASSWORD_REJECTED: &'static str = "password_rejected";
+pub static ERR_LOGIN_FAILED: &'static str = "login_failed";
+/// No stored room message has the given id.
+pub static ERR_NO_SUCH_MESSAGE: &'static str = "no_such_message";
+/// The client may not do that, e.g. edit someone else's message.
+pub static ERR_FORBIDDEN: &'static str = "forbidden";
+
+/// Event ids reserved in storage at a time; each reservation waits on the
+/// disk, so ids are set aside in blocks.
+static ID_RESERVATION: u64 = 1000;
+
+/// Password hashes and checks which may wait for the hashing thread; more
+/// are refused until it catches up.
+static HASH_QUEUE_LEN: usize = 32;
+
+/// Longest custom status text accepted, in characters.
+pub static MAX_STATUS_LEN: usize = 100;
+/// Longest room topic accepted, in characters.
+pub static MAX_TOPIC_LEN: usize = 250;
+pub static MAX_DESCRIPTION_LEN: usize = 1000;
+
+/// Whether a client is around to chat.
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable,
+         Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    Away,
+    DoNotDisturb,
+}
+
+/// A room member's presence, as listed in a `RoomRoster`.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+pub struct MemberPresence {
+    client_id: i64,
+    presence: Presence,
+    status: Option<String>,
+}
+
+/// Milliseconds since the Unix epoch (UTC).
+pub fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    since_epoch.as_secs() * 1000 + (since_epoch.subsec_nanos() / 1000000) as u64
+}
+
+/// Messages from the server.
+///
+/// Events broadcast to more than one client carry an `id`, increasing in the
+/// order events happen, and a `timestamp` in milliseconds since the epoch.
+///
+/// The serde encoding is the wire protocol (see `protocol`); the
+/// `rustc_serialize` one is used for storage and legacy clients.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
-    /// messages from the server
+    #[serde(rename = "text")]
     TextMessage{
         message: String,
         client_id: i64,
+        room: String,
+        id: u64,
+        timestamp: u64,
+        /// client_ids of everyone the message mentions
+        mentions: Vec<i64>,
+        /// the message this one replies to, if any
+        parent_id: Option<u64>,
     },
     UserHangup{
         client_id: i64,
+        id: u64,
+        timestamp: u64,
     },
     UsernameRegistration{
         name: String,
         client_id: i64,
+        id: u64,
+        timestamp: u64,
     },
-    ClientAcknowledgement{
+    /// a client with a name took another one
+    UsernameChanged{
+        old: String,
+        new: String,
         client_id: i64,
+        id: u64,
+        timestamp: u64,
     },
-    UsernameInUse{
-        name: String,
+    ClientAcknowledgement{
+        client_id: i64,
+        /// pass as `?resume=` when reconnecting to pick up where this
+        /// connection left off
+        resume_token: String,
+        /// protocol version this connection speaks
+        protocol_version: u32,
     },
     ClientIdUsernameMappings{
         client_id_usernames: Vec<ClientIdUsername>,
     },
+    RoomJoined{
+        room: String,
+        client_id: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    RoomLeft{
+        room: String,
+        client_id: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    RoomRoster{
+        room: String,
+        client_ids: Vec<i64>,
+        /// presence of each member
+        members: Vec<MemberPresence>,
+    },
+    RoomList{
+        rooms: Vec<RoomSummary>,
+    },
+    /// sent with the roster on joining a room; `created_by` is an account
+    /// name, and `created_at` milliseconds since the epoch
+    RoomInfo{
+        room: String,
+        topic: Option<String>,
+        description: Option<String>,
+        created_by: Option<String>,
+        created_at: Option<u64>,
+    },
+    /// `client_id` changed the room's topic or description
+    TopicChanged{
+        room: String,
+        topic: Option<String>,
+        description: Option<String>,
+        client_id: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    /// the room message `message_id` now reads `message`; `client_id` made
+    /// the edit
+    MessageEdited{
+        message_id: u64,
+        room: String,
+        message: String,
+        client_id: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    MessageDeleted{
+        message_id: u64,
+        room: String,
+        client_id: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    /// edits made to a room message, oldest first
+    MessageEdits{
+        message_id: u64,
+        edits: Vec<MessageEdit>,
+    },
+    /// the message `parent_id` now has `reply_count` replies
+    ThreadUpdated{
+        parent_id: u64,
+        room: String,
+        reply_count: usize,
+    },
+    /// everyone's reactions to a room message, after one was added or
+    /// removed
+    ReactionsUpdated{
+        message_id: u64,
+        room: String,
+        reactions: Vec<Reaction>,
+    },
+    /// a message followed by its replies, oldest first
+    Thread{
+        parent_id: u64,
+        room: String,
+        messages: Vec<ServerMessage>,
+    },
+    /// sent to each client a room message mentions, wherever it is;
+    /// `client_id` is the sender's
+    Mentioned{
+        message_id: u64,
+        room: String,
+        message: String,
+        client_id: i64,
+    },
+    /// a client's presence or status text changed
+    PresenceChanged{
+        client_id: i64,
+        presence: Presence,
+        status: Option<String>,
+        id: u64,
+        timestamp: u64,
+    },
+    TypingStarted{
+        room: String,
+        client_id: i64,
+    },
+    TypingStopped{
+        room: String,
+        client_id: i64,
+    },
+    DirectMessage{
+        message: String,
+        from: i64,
+        to: i64,
+        id: u64,
+        timestamp: u64,
+    },
+    History{
+        room: String,
+        messages: Vec<ServerMessage>,
+        /// reply counts of the messages which have replies
+        threads: Vec<ThreadSummary>,
+        /// reactions to the messages which have any
+        reactions: Vec<MessageReactions>,
+    },
+    AccountCreated{
+        name: String,
+    },
+    /// text from the server for one client, such as a command's output
+    Notice{
+        message: String,
+        request_id: Option<String>,
+    },
+    /// a client request was refused; `code` is one of the `ERR_*` codes,
+    /// and `request_id` echoes the request's, if it had one
+    Error{
+        code: String,
+        message: String,
+        request_id: Option<String>,
+    },
+}
+
+impl ServerMessage {
+    pub fn error(code: &str, message: String, request_id: Option<String>)
+                 -> ServerMessage {
+        ServerMessage::Error{
+            code: String::from(code),
+            message: message,
+            request_id: request_id,
+        }
+    }
+
+    /// The event id of broadcast messages.
+    pub fn id(&self) -> Option<u64> {
+        match *self {
+            ServerMessage::TextMessage{id, ..} => Some(id),
+            ServerMessage::UserHangup{id, ..} => Some(id),
+            ServerMessage::UsernameRegistration{id, ..} => Some(id),
+            ServerMessage::UsernameChanged{id, ..} => Some(id),
+            ServerMessage::PresenceChanged{id, ..} => Some(id),
+            ServerMessage::MessageEdited{id, ..} => Some(id),
+            ServerMessage::MessageDeleted{id, ..} => Some(id),
+            ServerMessage::RoomJoined{id, ..} => Some(id),
+            ServerMessage::RoomLeft{id, ..} => Some(id),
+            ServerMessage::TopicChanged{id, ..} => Some(id),
+            ServerMessage::DirectMessage{id, ..} => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The message a reply answers.
+    pub fn parent_id(&self) -> Option<u64> {
+        match *self {
+            ServerMessage::TextMessage{parent_id, ..} => parent_id,
+            _ => None,
+        }
+    }
+
+    /// Replaces the text of a text message.
+    pub fn set_text(&mut self, text: String) {
+        match *self {
+            ServerMessage::TextMessage{ref mut message, ..} => *message = text,
+            _ => (),
+        }
+    }
+
+    /// Identifies messages which make an earlier queued message of the same
+    /// key redundant, for the `Coalesce` overflow policy.
+    pub fn coalesce_key(&self) -> Option<(&'static str, i64)> {
+        match *self {
+            ServerMessage::UsernameRegistration{client_id, ..} => {
+                Some(("UsernameRegistration", client_id))
+            },
+            ServerMessage::ClientIdUsernameMappings{..} => {
+                Some(("ClientIdUsernameMappings", 0))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Outbound queue statistics for one connection.
+#[derive(Debug, RustcEncodable)]
+pub struct ClientStats {
+    client_id: i64,
+    conn_id: u64,
+    queue_depth: usize,
+    dropped: u64,
 }
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, RustcDecodable, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
     /// messages from clients
+    /// a `parent_id` makes the message a reply to that message, which must
+    /// be in the same room; answering a reply adds to the same thread
+    #[serde(rename = "text")]
     TextMessage{
         message: String,
+        parent_id: Option<u64>,
     },
     UsernameRegistration{
         name: String,
+    },
+    RoomTextMessage{
+        room: String,
+        message: String,
+        parent_id: Option<u64>,
+    },
+    JoinRoom{
+        room: String,
+    },
+    LeaveRoom{
+        room: String,
+    },
+    ListRooms,
+    DirectMessage{
+        /// recipient's client_id or, failing that, username
+        to: String,
+        message: String,
+    },
+    FetchHistory{
+        room: String,
+        /// only return messages with a lower id than this
+        before: Option<u64>,
+        limit: usize,
+    },
+    /// creates an account for `name` and logs in as it
+    RegisterAccount{
+        name: String,
+        password: String,
+    },
+    Login{
+        name: String,
+        password: String,
+    },
+    /// replaces the text of one of the client's room messages
+    EditMessage{
+        id: u64,
+        message: String,
+    },
+    DeleteMessage{
+        id: u64,
+    },
+    FetchEdits{
+        id: u64,
+    },
+    /// fetches a message and its replies
+    FetchThread{
+        id: u64,
+    },
+    /// `emoji` is the emoji itself or a shortcode such as `:thumbsup:`
+    AddReaction{
+        message_id: u64,
+        emoji: String,
+    },
+    RemoveReaction{
+        message_id: u64,
+        emoji: String,
+    },
+    /// an empty topic clears it
+    SetTopic{
+        room: String,
+        topic: String,
+    },
+    SetDescription{
+        room: String,
+        description: String,
+    },
+    /// `status` is optional free text, e.g. "at lunch"
+    SetPresence{
+        presence: Presence,
+        status: Option<String>,
+    },
+    /// sent every few seconds while composing a message for a room
+    TypingStarted{
+        room: String,
+    },
+    TypingStopped{
+        room: String,
+    },
+    /// any `type` this server doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+impl ClientMessage {
+    /// The name of the room a message is about, if any.
+    pub fn room_mut(&mut self) -> Option<&mut String> {
+        match *self {
+            ClientMessage::RoomTextMessage{ref mut room, ..} => Some(room),
+            ClientMessage::JoinRoom{ref mut room} => Some(room),
+            ClientMessage::LeaveRoom{ref mut room} => Some(room),
+            ClientMessage::FetchHistory{ref mut room, ..} => Some(room),
+            ClientMessage::SetTopic{ref mut room, ..} => Some(room),
+            ClientMessage::SetDescription{ref mut room, ..} => Some(room),
+            ClientMessage::TypingStarted{ref mut room} => Some(room),
+            ClientMessage::TypingStopped{ref mut room} => Some(room),
+            _ => None,
+        }
     }
 }
 
+/// One open WebSocket belonging to a client. A client may have several at
+/// once, e.g. on a laptop and a phone.
 #[derive(Clone)]
-pub struct ChatClient {
-    pub name: Option<String>,
-    pub client_id: i64,
-    msg_tx: Sender<ServerMessage>,
-    server: Arc<Mutex<ChatServer>>,
+pub struct Connection {
+    conn_id: u64,
+    /// current token for resuming this connection
+    resume_token: Option<String>,
+    /// when the socket dropped, if the connection is awaiting resumption
+    detached_at: Option<u64>,
+    /// the dropped connection this one picks up from, if any
+    resumes: Option<u64>,
+    /// wire protocol version of this connection
+    protocol_version: u32,
+    codec: Codec,
+    queue: OutboundQueue,
 }
 
-impl ChatClient {
-    pub fn run(request: Request) {
-        let (tx, rx) = channel::<ServerMessage>();
-        let local_tx = tx.clone();
-        let mut client = ChatClient {
-            name: None, msg_tx: tx, server: request.chat_server.clone(),
-            client_id: rand::random()};
-        let stream: TcpStream = request.stream.into_inner().unwrap();
-        let stream2: TcpStream = stream.try_clone().unwrap();
-        let buf_stream = BufStream::new(stream);
-        let buf_stream2 = BufStream::new(stream2);
-
-        // create server listener thread
-        client.start_server_listener(rx, buf_stream);
-        request.chat_server.lock().unwrap().add_client(client.clone());
-
-        // start listening to client via stream
-        // this function blocks until the user hangs up
-        client.start_client_listener(buf_stream2);
-
-        // when client hangs up, kill the server listener thread
-        {
-            let mut server = request.chat_server.lock().unwrap();
-            server.dispatch_message(
-                ServerMessage::UserHangup{
-                    client_id: client.client_id.clone(),
-                });
-            server.rm_client(&client);
-        }
-    }
-
-    fn start_server_listener
-        <T: Read + Write + Send + 'static>(&self, rx: Receiver<ServerMessage>,
-                                     stream: BufStream<T>) {
+impl Connection {
+    fn start_writer(&self, stream: BufStream<Stream>) {
         let mut stream = stream;
-        let client = self.clone();
+        let conn = self.clone();
         thread::spawn(move || {
-            for msg in rx.iter() {
-                match msg {
-                    ServerMessage::UserHangup{client_id: client_id, ..}
-                        if client_id == client.client_id => {
-                            // case: client has signaled it's time to stop
+            loop {
+                let msg = match conn.queue.pop() {
+                    Outbound::Message(msg) => msg,
+                    Outbound::Close(code) => {
+                        // case: client fell too far behind or was replaced;
+                        // hang up on it
+                        let _ = ws::write_close(&mut stream, code);
+                        let _ = stream.get_ref().shutdown(Shutdown::Both);
+                        return
+                    },
+                    Outbound::Pong(data) => {
+                        if ws::write_pong(&mut stream, &data).is_err() {
+                            let _ = stream.get_ref().shutdown(Shutdown::Both);
                             return
+                        }
+                        continue
                     },
-                    _ => (),
-                }
+                    Outbound::Detached => return,
+                };
 
-                let text = json::encode(&msg).unwrap();
-                ws::write_stream(&mut stream, &text.into_bytes());
+                let data = conn.codec.encode(&msg, conn.protocol_version);
+                let written = if conn.codec.is_binary() {
+                    ws::write_binary(&mut stream, &data)
+                } else {
+                    ws::write_stream(&mut stream, &data)
+                };
+                if written.is_err() {
+                    // case: socket is dead or write timed out
+                    let _ = stream.get_ref().shutdown(Shutdown::Both);
+                    return
+                }
             }
         });
     }
 
-    fn start_client_listener<T: Read + Write>(&mut self, mut stream: BufStream<T>) {
+    /// Reads messages from the socket and hands them to the server until
+    /// the client hangs up. Returns whether it did so with a close frame,
+    /// rather than the connection dropping.
+    fn listen<T: Read + Write>(&self, server: &ChatServerHandle,
+                               mut stream: BufStream<T>) -> bool {
         loop {
             match ws::read_stream(&mut stream) {
-                Ok(data) => {
-                    let message = match from_utf8(&data[..]) {
-                        Ok(message) => message,
-                        Err(e) => break
-                    };
-                    match json::decode(message) {
-                        Ok(msg) => {
-                            match msg {
-                                ClientMessage::UsernameRegistration{name: ref name} => {
-                                    self.name = Some(name.clone());
-                                },
-                                _ => (),
-                            }
-                            self.server.lock().unwrap()
-                                .handle_client_msg(msg, self.client_id);
+                Ok((0x8, _)) => {
+                    // case: client is closing; answer in kind and hang up
+                    self.queue.close(CLOSE_NORMAL);
+                    return true;
+                },
+                Ok((0x9, data)) => self.queue.pong(data),
+                Ok((0x1, data)) | Ok((0x2, data)) => {
+                    match self.codec.decode(&data[..], self.protocol_version) {
+                        Ok((msg, request_id)) => {
+                            // names are decided by the server, which keeps
+                            // the only copy that matters
+                            server.handle_client_msg(msg, self.conn_id,
+                                                     request_id);
                         }
-                        Err(e) => {
-                            println!("Bad message from client: {} {}",
-                                     message.trim(), e);
+                        Err(error) => {
+                            println!("Bad message from client: {:?}", error);
+                            self.queue.push(error);
                         }
                     }
                 },
+                Ok(_) => {
+                    // case: pong or continuation frame; nothing to do
+                },
                 Err(e) => {
                     // case: user hung up; return and bail
-                    break;
+                    return false;
                 }
             }
         }
     }
-
-    pub fn send_msg(&self, event: ServerMessage) {
-        self.msg_tx.send(event);
-    }
 }
 
-pub struct ChatServer {
-    clients: HashMap<i64, ChatClient>,
-    client_usernames: HashSet<String>,
+/// A user of the chat, and every connection it has open.
+#[derive(Clone)]
+pub struct ChatClient {
+    pub name: Option<String>,
+    pub client_id: i64,
+    /// account this client has logged in as
+    pub account: Option<String>,
+    /// identity established by a session token on connect
+    pub identity: Option<String>,
+    rate_limiter: RateLimiter,
+    pub presence: Presence,
+    pub status: Option<String>,
+    /// whether `presence` was set to away by idle detection rather than the
+    /// client
+    auto_away: bool,
+    /// when the client last sent anything
+    last_active: u64,
+    connections: Vec<Connection>,
 }
 
-impl ChatServer {
-    pub fn new() -> ChatServer {
-        ChatServer {
-            clients: HashMap::new(),
-            client_usernames: HashSet::new(),
+impl ChatClient {
+    pub fn run(request: Request, identity: Option<String>,
+               resume_token: Option<String>, protocol_version: u32,
+               codec: Codec) {
+        let queue = OutboundQueue::new(request.config.outbound_queue_len,
+                                       request.config.overflow_policy);
+        let resumed = match resume_token {
+            Some(token) => {
+                request.chat_server.claim_session(token, identity.clone())
+            },
+            None => None,
+        };
+        let client_id = match (resumed, &identity) {
+            (Some((client_id, _)), _) => client_id,
+            (None, &Some(ref identity)) => {
+                auth::identity_client_id(&identity[..])
+            },
+            (None, &None) => auth::random_client_id(),
+        };
+        let conn = Connection {
+            conn_id: rand::random(), resume_token: None, detached_at: None,
+            resumes: resumed.map(|(_, conn_id)| conn_id),
+            protocol_version: protocol_version, codec: codec, queue: queue};
+        let client = ChatClient {
+            name: None, client_id: client_id, account: None,
+            identity: identity,
+            rate_limiter: RateLimiter::new(request.config.rate_limit,
+                                           request.config.rate_burst,
+                                           now_millis()),
+            presence: Presence::Online, status: None, auto_away: false,
+            last_active: now_millis(),
+            connections: vec![conn.clone()]};
+        let stream: Stream = request.stream.into_inner().unwrap();
+        let write_timeout = Duration::from_secs(
+            request.config.write_timeout_secs);
+        let _ = stream.set_write_timeout(Some(write_timeout));
+        let stream2: Stream = stream.try_clone().unwrap();
+        let buf_stream = BufStream::new(stream);
+        let buf_stream2 = BufStream::new(stream2);
+
+        // create server listener thread
+        conn.start_writer(buf_stream);
+        request.chat_server.add_client(client);
+
+        // start listening to client via stream
+        // this function blocks until the user hangs up
+        let closed = conn.listen(&request.chat_server, buf_stream2);
+
+        // when client hangs up, kill the server listener thread
+        if closed {
+            request.chat_server.hang_up(conn.conn_id);
+        } else {
+            request.chat_server.disconnect(conn.conn_id);
         }
     }
+}
 
-    pub fn add_client(&mut self, client: ChatClient) {
-        let client_id = client.client_id;
+/// Requests made of the chat server by client connection threads.
+enum ServerCommand {
+    /// a new connection, in a client of its own
+    AddClient(ChatClient),
+    /// a connection's socket closed
+    Disconnect(u64),
+    /// a connection was closed by its client, so won't be resumed
+    HangUp(u64),
+    /// a message from a connection, with the client's request_id
+    ClientMsg(ClientMessage, u64, Option<String>),
+    Stats(Sender<Vec<ClientStats>>),
+    /// exchanges a resume token for the client_id and conn_id of the
+    /// connection it resumes, given the identity verified on this upgrade
+    ClaimSession(String, Option<String>, Sender<Option<(i64, u64)>>),
+    /// sent every second to drive timeouts
+    Tick,
+    /// a new account's password has been hashed off the server thread
+    AccountHashed{
+        client_id: i64,
+        request_id: Option<String>,
+        name: String,
+        password_hash: String,
+    },
+    /// a login attempt's password has been checked off the server thread
+    LoginVerified{
+        client_id: i64,
+        request_id: Option<String>,
+        name: String,
+        ok: bool,
+    },
+}
 
-        // send ack message to new client
-        let ack_msg = ServerMessage::ClientAcknowledgement{
-            client_id: client_id};
-        client.send_msg(ack_msg);
+/// Password work for the hashing thread, whose results come back to the
+/// server thread as `AccountHashed` and `LoginVerified`.
+enum HashJob {
+    /// hash a new account's password
+    Hash{
+        client_id: i64,
+        request_id: Option<String>,
+        name: String,
+        password: String,
+    },
+    /// check a login's password against the account's hash, if it has one
+    Verify{
+        client_id: i64,
+        request_id: Option<String>,
+        name: String,
+        password: String,
+        password_hash: Option<String>,
+    },
+}
 
-        let mut cid_usernames = Vec::new();
-        for (k, v) in self.clients.iter() {
-            match v.name {
-                Some(ref name) => {
-                    cid_usernames.push(ClientIdUsername{
-                        client_id: k.clone(),
-                        username: name.clone(),
-                    });
-                },
-                None => ()
-            }
+/// Does password work, one job at a time, until the server stops.
+fn hash_passwords(jobs: Receiver<HashJob>, cmd_tx: Sender<ServerCommand>) {
+    for job in jobs.iter() {
+        let cmd = match job {
+            HashJob::Hash{client_id, request_id, name, password} => {
+                match bcrypt::hash(&password[..], bcrypt::DEFAULT_COST) {
+                    Ok(password_hash) => ServerCommand::AccountHashed{
+                        client_id: client_id,
+                        request_id: request_id,
+                        name: name,
+                        password_hash: password_hash,
+                    },
+                    Err(e) => {
+                        println!("failed to hash password: {}", e);
+                        continue;
+                    },
+                }
+            },
+            HashJob::Verify{client_id, request_id, name, password,
+                            password_hash} => {
+                let ok = match password_hash {
+                    Some(hash) => {
+                        bcrypt::verify(&password[..], &hash[..])
+                            .unwrap_or(false)
+                    },
+                    None => false,
+                };
+                ServerCommand::LoginVerified{
+                    client_id: client_id, request_id: request_id,
+                    name: name, ok: ok}
+            },
+        };
+        if cmd_tx.send(cmd).is_err() {
+            return;
         }
+    }
+}
 
-        let cid_username_msg = ServerMessage::ClientIdUsernameMappings{
-            client_id_usernames: cid_usernames,
-        };
+/// Cheaply clonable handle to a running `ChatServer`.
+///
+/// Every method enqueues a command for the server thread and returns
+/// immediately, so connection threads never wait on one another.
+#[derive(Clone)]
+pub struct ChatServerHandle {
+    cmd_tx: Sender<ServerCommand>,
+}
 
-        client.send_msg(cid_username_msg);
+impl ChatServerHandle {
+    pub fn add_client(&self, client: ChatClient) {
+        let _ = self.cmd_tx.send(ServerCommand::AddClient(client));
+    }
 
-        self.clients.insert(client.client_id, client);
-        println!("client joined: {} ({} total clients)", client_id,
-                 self.clients.len());
+    pub fn disconnect(&self, conn_id: u64) {
+        let _ = self.cmd_tx.send(ServerCommand::Disconnect(conn_id));
+    }
 
+    pub fn hang_up(&self, conn_id: u64) {
+        let _ = self.cmd_tx.send(ServerCommand::HangUp(conn_id));
     }
 
-    pub fn rm_client(&mut self, client: &ChatClient) {
-        let _ = self.clients.remove(&client.client_id);
-        println!("client left: {} ({} total clients)", &client.client_id,
-                 self.clients.len());
+    pub fn handle_client_msg(&self, msg: ClientMessage, conn_id: u64,
+                             request_id: Option<String>) {
+        let _ = self.cmd_tx.send(
+            ServerCommand::ClientMsg(msg, conn_id, request_id));
+    }
 
-        match client.name {
-            Some(ref username) => {
-                self.client_usernames.remove(username);
-            },
-            None => (),
-        }
+    /// Looks up the connection a resume token belongs to, returning its
+    /// client_id and conn_id. Tokens are single use. When session tokens
+    /// are required, only the same identity may resume a connection.
+    pub fn claim_session(&self, resume_token: String,
+                         identity: Option<String>) -> Option<(i64, u64)> {
+        let (tx, rx) = channel();
+        let _ = self.cmd_tx.send(ServerCommand::ClaimSession(resume_token,
+                                                             identity, tx));
+        rx.recv().unwrap_or(None)
     }
 
-    pub fn register_username(&mut self, username: &String) -> bool {
-        if self.client_usernames.contains(username) {
-            false
-        } else {
-            self.client_usernames.insert(username.clone());
-            true
-        }
+    /// Fetches outbound queue statistics for every connected client.
+    pub fn stats(&self) -> Vec<ClientStats> {
+        let (tx, rx) = channel();
+        let _ = self.cmd_tx.send(ServerCommand::Stats(tx));
+        rx.recv().unwrap_or(Vec::new())
     }
+}
 
-    pub fn dispatch_message(&self, msg: ServerMessage) {
-        println!("client msg: {:?}", msg);
-        for client in self.clients.values() {
-            client.send_msg(msg.clone())
+/// A named group of clients which receive each other's text messages.
+struct Room {
+    members: HashSet<i64>,
+    /// topic, description and creation details, as stored
+    record: RoomRecord,
+}
+
+impl Room {
+    fn new(record: RoomRecord) -> Room {
+        Room {
+            members: HashSet::new(),
+            record: record,
         }
     }
+}
 
-    pub fn handle_client_msg(&mut self, msg: ClientMessage, client_id: i64) {
-        let server_msg = match msg {
-            ClientMessage::TextMessage{message: message} => {
-                ServerMessage::TextMessage{message: message,
-                                           client_id: client_id}
-            },
-            ClientMessage::UsernameRegistration{name: name} => {
-                let username_opt;
+/// Chat state, owned exclusively by the server thread started by
+/// `ChatServer::start()`.
+pub struct ChatServer {
+    clients: HashMap<i64, ChatClient>,
+    /// `username::key()` of every name in use
+    client_usernames: HashSet<String>,
+    rooms: HashMap<String, Room>,
+    history: History,
+    typing: TypingTracker,
+    reactions: Reactions,
+    /// slash commands clients may type
+    commands: Commands,
+    storage: Box<Storage>,
+    config: Arc<Config>,
+    next_message_id: u64,
+    /// ids below this are reserved in storage and may be handed out
+    reserved_ids: u64,
+    /// client_id and conn_id of the connection each outstanding resume
+    /// token belongs to
+    resume_tokens: HashMap<String, (i64, u64)>,
+    /// client_id of the client each connection belongs to
+    connection_owners: HashMap<u64, i64>,
+    /// feeds the thread hashing and checking passwords
+    hash_jobs: SyncSender<HashJob>,
+}
 
-                {
-                    let client = self.clients.get(&client_id).unwrap();
-                    username_opt = client.name.clone();
-                }
+impl ChatServer {
+    fn new(config: Arc<Config>, commands: Commands,
+           cmd_tx: Sender<ServerCommand>) -> ChatServer {
+        let storage_path = config.storage_path.as_ref().map(|p| &p[..]);
+        let storage = storage::open(storage_path).unwrap();
 
-                match username_opt {
-                    Some(ref username) if username == &name => {
-                        println!("Client reregisered username {}", name);
-                        return;
-                    },
-                    _ => (),
-                };
+        let mut rooms = HashMap::new();
+        let lobby = RoomRecord {
+            name: String::from(LOBBY),
+            topic: None,
+            description: None,
+            created_by: None,
+            created_at: None,
+        };
+        rooms.insert(String::from(LOBBY), Room::new(lobby));
+        // the lobby is only stored once its topic is set
+        for room in storage.rooms() {
+            rooms.insert(room.name.clone(), Room::new(room));
+        }
+        let room_names = rooms.keys().cloned().collect();
+        let history = History::load(config.history_len, &*storage,
+                                    &room_names);
+        let mut reactions = Reactions::new();
+        for reaction in storage.reactions() {
+            reactions.add(reaction.message_id, &reaction.emoji[..],
+                          reaction.client_id);
+        }
+        let next_message_id = cmp::max(
+            storage.reserved_ids(),
+            storage.last_message_id().map_or(0, |id| id + 1));
+        // bcrypt is deliberately slow; keep it off the server thread
+        let (hash_jobs, hash_rx) = sync_channel(HASH_QUEUE_LEN);
+        thread::spawn(move || hash_passwords(hash_rx, cmd_tx));
 
-                if !self.register_username(&name) {
-                    // case: username is in use
-                    let msg = ServerMessage::UsernameInUse{name: name.clone()};
-                    let client = self.clients.get(&client_id).unwrap();
-                    client.send_msg(msg);
+        ChatServer {
+            clients: HashMap::new(),
+            client_usernames: HashSet::new(),
+            rooms: rooms,
+            history: history,
+            typing: TypingTracker::new(config.typing_timeout_secs * 1000),
+            reactions: reactions,
+            commands: commands,
+            storage: storage,
+            config: config,
+            next_message_id: next_message_id,
+            reserved_ids: next_message_id,
+            resume_tokens: HashMap::new(),
+            connection_owners: HashMap::new(),
+            hash_jobs: hash_jobs,
+        }
+    }
+
+    /// Moves a new server onto its own thread and returns a handle to it.
+    pub fn start(config: Arc<Config>, commands: Commands) -> ChatServerHandle {
+        let (cmd_tx, cmd_rx) = channel::<ServerCommand>();
+        let mut server = ChatServer::new(config, commands, cmd_tx.clone());
+        thread::spawn(move || {
+            server.run(cmd_rx);
+        });
+
+        let tick_tx = cmd_tx.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                if tick_tx.send(ServerCommand::Tick).is_err() {
                     return;
-                } else {
-                    // case: username is free
-                    match username_opt {
-                        Some(ref username) if username == &name => {
-                            self.client_usernames.remove(username);
-                        },
-                        _ => (),
-                    };
-                    let mut client = self.clients.get_mut(&client_id).unwrap();
-                    client.name = Some(name.clone());
-                    println!("Client {} is now called {}", client.client_id,
-                             name);
                 }
-                ServerMessage::UsernameRegistration{
-                    name: name, client_id: client_id}
             }
-        };
-        self.dispatch_message(server_msg);
+        });
+        ChatServerHandle { cmd_tx: cmd_tx }
+    }
+
+    /// Assigns the next event id and timestamp.
+    fn stamp(&mut self) -> (u64, u64) {
+        if self.next_message_id >= self.reserved_ids {
+            let below = self.next_message_id + ID_RESERVATION;
+            if let Err(e) = self.storage.reserve_ids(below) {
+                println!("failed to store reserved ids: {}", e);
+            }
+            self.reserved_ids = below;
+        }
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        (id, now_millis())
+    }
+
+    fn run(&mut self, cmd_rx: Receiver<ServerCommand>) {
+        for cmd in cmd_rx.iter() {
+            match cmd {
+                ServerCommand::AddClient(client) => self.add_client(client),
+                ServerCommand::Disconnect(conn_id) => {
+                    self.disconnect(conn_id);
+                },
+                ServerCommand::HangUp(conn_id) => {
+                    let client_id = self.connection_owners.get(&conn_id)
+                        .cloned();
+                    if let Some(client_id) = client_id {
+                        self.remove_connection(client_id, conn_id);
+                    }
+                },
+                ServerCommand::ClientMsg(msg, conn_id, request_id) => {
+                    let client_id = self.connection_owners.get(&conn_id)
+                        .cloned();
+                    if let Some(client_id) = client_id {
+                        self.handle_client_msg(msg, client_id, request_id)
+                    }
+                },
+                ServerCommand::Stats(reply_tx) => {
+                    let _ = reply_tx.send(self.stats());
+                },
+                ServerCommand::AccountHashed{client_id, request_id, name,
+                                             password_hash} => {
+                    self.create_account(client_id, request_id, name,
+                                        password_hash);
+                },
+                ServerCommand::LoginVerified{client_id, request_id, name,
+                                             ok} => {
+                    self.finish_login(client_id, request_id, name, ok);
+                },
+                ServerCommand::ClaimSession(resume_token, identity,
+                                            reply_tx) => {
+                    let resumed = self.claim_session(resume_token, identity);
+                    let _ = reply_tx.send(resumed);
+                },
+                ServerCommand::Tick => self.tick(),
+            }
+        }
+    }
+
+    /// Exchanges a resume token for the connection it resumes. With
+    /// session tokens required, a token is refused (and kept) unless the
+    /// identity verified on this upgrade is the one its client has.
+    fn claim_session(&mut self, resume_token: String,
+                     identity: Option<String>) -> Option<(i64, u64)> {
+        let client_id = match self.resume_tokens.get(&resume_token) {
+            Some(&(client_id, _)) => client_id,
+            None => return None,
+        };
+        if self.config.auth_secret.is_some() {
+            let claimed = self.clients.get(&client_id)
+                .and_then(|c| c.identity.as_ref());
+            if identity.is_none() || claimed != identity.as_ref() {
+                println!("refused resuming {} as {:?}", client_id, identity);
+                return None;
+            }
+        }
+        self.resume_tokens.remove(&resume_token)
+    }
+
+    fn tick(&mut self) {
+        let now = now_millis();
+        let grace_ms = self.config.resume_grace_secs * 1000;
+        let mut expired = Vec::new();
+        for client in self.clients.values() {
+            for conn in client.connections.iter() {
+                let detached_for = conn.detached_at
+                    .map(|t| now.saturating_sub(t));
+                if detached_for.map_or(false, |d| d >= grace_ms) {
+                    expired.push((client.client_id, conn.conn_id));
+                }
+            }
+        }
+        for (client_id, conn_id) in expired {
+            println!("connection expired: {} ({})", conn_id, client_id);
+            self.remove_connection(client_id, conn_id);
+        }
+
+        for (room, client_id) in self.typing.expire(now) {
+            self.announce_typing_stopped(&room[..], client_id);
+        }
+
+        let idle_ms = self.config.idle_timeout_secs * 1000;
+        let idle: Vec<i64> = self.clients.values()
+            .filter(|c| c.presence == Presence::Online
+                    && now.saturating_sub(c.last_active) >= idle_ms)
+            .map(|c| c.client_id)
+            .collect();
+        for client_id in idle {
+            if let Some(client) = self.clients.get_mut(&client_id) {
+                client.presence = Presence::Away;
+                client.auto_away = true;
+            }
+            self.announce_presence(client_id);
+        }
+    }
+
+    /// Tells everyone a client's current presence and status.
+    fn announce_presence(&mut self, client_id: i64) {
+        let (presence, status) = match self.clients.get(&client_id) {
+            Some(client) => (client.presence, client.status.clone()),
+            None => return,
+        };
+        let (id, timestamp) = self.stamp();
+        self.dispatch_message(ServerMessage::PresenceChanged{
+            client_id: client_id,
+            presence: presence,
+            status: status,
+            id: id,
+            timestamp: timestamp,
+        });
+    }
+
+    /// Handles a connection's socket closing. The connection is held for
+    /// the resume grace period, collecting messages, before it is removed.
+    fn disconnect(&mut self, conn_id: u64) {
+        let client_id = match self.connection_owners.get(&conn_id) {
+            Some(&client_id) => client_id,
+            None => return,
+        };
+
+        if self.config.resume_grace_secs == 0 {
+            self.remove_connection(client_id, conn_id);
+            return;
+        }
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            for conn in client.connections.iter_mut() {
+                if conn.conn_id == conn_id {
+                    conn.queue.detach();
+                    conn.detached_at = Some(now_millis());
+                }
+            }
+        }
+    }
+
+    /// Drops one of a client's connections, ending the client's session if
+    /// it was the last.
+    fn remove_connection(&mut self, client_id: i64, conn_id: u64) {
+        let remaining = match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.connections.retain(|c| c.conn_id != conn_id);
+                client.connections.len()
+            },
+            None => return,
+        };
+        self.forget_connection(conn_id);
+
+        if remaining == 0 {
+            let client = self.clients[&client_id].clone();
+            self.end_session(&client);
+        }
+    }
+
+    /// Drops a connection's resume token and owner.
+    fn forget_connection(&mut self, conn_id: u64) {
+        self.connection_owners.remove(&conn_id);
+        self.resume_tokens.retain(|_, &mut (_, id)| id != conn_id);
+    }
+
+    /// Removes a client and tells everyone it has left.
+    fn end_session(&mut self, client: &ChatClient) {
+        self.rm_client(client);
+        let (id, timestamp) = self.stamp();
+        self.dispatch_message(
+            ServerMessage::UserHangup{
+                client_id: client.client_id,
+                id: id,
+                timestamp: timestamp,
+            });
+    }
+
+    pub fn add_client(&mut self, client: ChatClient) {
+        let client_id = client.client_id;
+        let mut client = client;
+        let mut conn = client.connections.pop().unwrap();
+
+        let resume_token = format!("{:016x}{:016x}", rand::random::<u64>(),
+                                   rand::random::<u64>());
+        self.resume_tokens.insert(resume_token.clone(),
+                                  (client_id, conn.conn_id));
+        conn.resume_token = Some(resume_token.clone());
+        self.connection_owners.insert(conn.conn_id, client_id);
+
+        // send ack message to new client
+        let ack_msg = ServerMessage::ClientAcknowledgement{
+            client_id: client_id, resume_token: resume_token,
+            protocol_version: conn.protocol_version};
+        conn.queue.push(ack_msg);
+
+        if self.clients.contains_key(&client_id) {
+            self.add_connection(client_id, conn, client.identity);
+            return;
+        }
+
+        conn.queue.push(self.username_mappings());
+        client.connections.push(conn);
+
+        self.clients.insert(client.client_id, client);
+        println!("client joined: {} ({} total clients)", client_id,
+                 self.clients.len());
+
+        self.join_room(LOBBY, client_id);
+
+        // the identity becomes the client's username if it is a valid one;
+        // otherwise the client stays nameless until it picks one
+        let identity = self.clients[&client_id].identity.clone();
+        match identity.as_ref().map(|identity| username::normalize(identity)) {
+            Some(Ok(name)) => self.log_in(client_id, name, None),
+            Some(Err(e)) => {
+                let msg = ServerMessage::error(
+                    ERR_INVALID_USERNAME,
+                    format!("your identity can't be used as a username: {}",
+                            e),
+                    None);
+                self.send_to(&vec![client_id], msg);
+            },
+            None => (),
+        }
+    }
+
+    /// Adds another connection to an existing client, which arrived with a
+    /// resume token or the same identity. Nobody else sees anything change.
+    fn add_connection(&mut self, client_id: i64, conn: Connection,
+                      identity: Option<String>) {
+        println!("client {} has a new connection", client_id);
+        let mut conn = conn;
+
+        // pick up whatever a resumed connection didn't deliver, then hang it
+        // up in case it is still open
+        let resumed = match conn.resumes {
+            Some(old_conn_id) => self.clients[&client_id].connections.iter()
+                .filter(|c| c.conn_id == old_conn_id)
+                .cloned()
+                .next(),
+            None => None,
+        };
+        if let Some(resumed) = resumed {
+            for msg in resumed.queue.drain() {
+                conn.queue.push(msg);
+            }
+            resumed.queue.close(CLOSE_REPLACED);
+            if let Some(client) = self.clients.get_mut(&client_id) {
+                client.connections.retain(|c| c.conn_id != resumed.conn_id);
+            }
+            self.forget_connection(resumed.conn_id);
+        }
+        conn.resumes = None;
+
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            if client.identity.is_none() {
+                client.identity = identity;
+            }
+        }
+        self.greet_connection(client_id, conn);
+    }
+
+    /// Catches a connection up on the client's state and adds it to the
+    /// client.
+    fn greet_connection(&mut self, client_id: i64, conn: Connection) {
+        conn.queue.push(self.username_mappings());
+
+        // room membership is keyed by client_id, so it is shared
+        let room_names: Vec<String> = self.rooms.iter()
+            .filter(|&(_, room)| room.members.contains(&client_id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for room_name in room_names {
+            conn.queue.push(self.room_roster(&room_name[..]));
+            conn.queue.push(self.room_info(&room_name[..]));
+        }
+
+        self.connection_owners.insert(conn.conn_id, client_id);
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.connections.push(conn);
+        }
+    }
+
+    /// Moves every connection of one client to another, e.g. when it logs
+    /// in to an account the other already holds. The emptied client leaves.
+    fn merge_client(&mut self, from_id: i64, to_id: i64) {
+        let moved: Vec<Connection> = match self.clients.get_mut(&from_id) {
+            Some(client) => client.connections.drain(..).collect(),
+            None => return,
+        };
+        let from = self.clients[&from_id].clone();
+        self.end_session(&from);
+
+        println!("client {} merged into {}", from_id, to_id);
+        for conn in moved {
+            if let Some(ref token) = conn.resume_token {
+                self.resume_tokens.insert(token.clone(), (to_id, conn.conn_id));
+            }
+            // tell the connection who it is now
+            conn.queue.push(ServerMessage::ClientAcknowledgement{
+                client_id: to_id,
+                resume_token: conn.resume_token.clone().unwrap_or_default(),
+                protocol_version: conn.protocol_version,
+            });
+            self.greet_connection(to_id, conn);
+        }
+    }
+
+    fn username_mappings(&self) -> ServerMessage {
+        let mut cid_usernames = Vec::new();
+        for (k, v) in self.clients.iter() {
+            match v.name {
+                Some(ref name) => {
+                    cid_usernames.push(ClientIdUsername{
+                        client_id: k.clone(),
+                        username: name.clone(),
+                    });
+                },
+                None => ()
+            }
+        }
+
+        ServerMessage::ClientIdUsernameMappings{
+            client_id_usernames: cid_usernames,
+        }
+    }
+
+    pub fn rm_client(&mut self, client: &ChatClient) {
+        let _ = self.clients.remove(&client.client_id);
+        for conn in client.connections.iter() {
+            self.forget_connection(conn.conn_id);
+        }
+        self.typing.forget_client(client.client_id);
+        println!("client left: {} ({} total clients)", &client.client_id,
+                 self.clients.len());
+
+        match client.name {
+            Some(ref username) => {
+                self.client_usernames.remove(&username::key(&username[..]));
+            },
+            None => (),
+        }
+
+        for room in self.rooms.values_mut() {
+            room.members.remove(&client.client_id);
+        }
+    }
+
+    pub fn join_room(&mut self, room_name: &str, client_id: i64) {
+        if !self.rooms.contains_key(room_name) {
+            let record = RoomRecord {
+                name: String::from(room_name),
+                topic: None,
+                description: None,
+                created_by: self.clients.get(&client_id)
+                    .and_then(|c| c.account.clone()),
+                created_at: Some(now_millis()),
+            };
+            if let Err(e) = self.storage.save_room(&record) {
+                println!("failed to store room {}: {}", room_name, e);
+            }
+            self.rooms.insert(String::from(room_name), Room::new(record));
+        }
+
+        let is_new_member = match self.rooms.get_mut(room_name) {
+            Some(room) => room.members.insert(client_id),
+            None => false,
+        };
+
+        if is_new_member {
+            let (id, timestamp) = self.stamp();
+            self.dispatch_room_message(room_name, ServerMessage::RoomJoined{
+                room: String::from(room_name),
+                client_id: client_id,
+                id: id,
+                timestamp: timestamp,
+            });
+        }
+
+        let roster_msg = self.room_roster(room_name);
+        self.send_to(&vec![client_id], roster_msg);
+        let info_msg = self.room_info(room_name);
+        self.send_to(&vec![client_id], info_msg);
+
+        let messages = self.history.fetch(&*self.storage, room_name, None,
+                                          self.config.history_replay_len);
+        let history_msg = ServerMessage::History{
+            room: String::from(room_name),
+            threads: self.thread_summaries(&messages),
+            reactions: self.reaction_summaries(&messages),
+            messages: messages,
+        };
+        self.send_to(&vec![client_id], history_msg);
+    }
+
+    /// Snapshot of a room's members and their presence.
+    fn room_roster(&self, room_name: &str) -> ServerMessage {
+        let client_ids: Vec<i64> = match self.rooms.get(room_name) {
+            Some(room) => room.members.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        let members = client_ids.iter()
+            .filter_map(|id| self.clients.get(id))
+            .map(|client| {
+                MemberPresence {
+                    client_id: client.client_id,
+                    presence: client.presence,
+                    status: client.status.clone(),
+                }
+            })
+            .collect();
+        ServerMessage::RoomRoster{
+            room: String::from(room_name),
+            client_ids: client_ids,
+            members: members,
+        }
+    }
+
+    /// A room's topic, description and creation details.
+    fn room_info(&self, room_name: &str) -> ServerMessage {
+        let record = self.rooms.get(room_name).map(|room| &room.record);
+        ServerMessage::RoomInfo{
+            room: String::from(room_name),
+            topic: record.and_then(|r| r.topic.clone()),
+            description: record.and_then(|r| r.description.clone()),
+            created_by: record.and_then(|r| r.created_by.clone()),
+            created_at: record.and_then(|r| r.created_at),
+        }
+    }
+
+    /// Whether a client may change a room's topic and description.
+    /// Moderators may change any room's, and the account which created a
+    /// room may change its own. Rooms without a known creator, other than
+    /// the lobby, are open to all their members.
+    fn may_edit_room(&self, room_name: &str, client_id: i64) -> bool {
+        if self.is_moderator(client_id) {
+            return true;
+        }
+        let created_by = match self.rooms.get(room_name) {
+            Some(room) => room.record.created_by.clone(),
+            None => return false,
+        };
+        match created_by {
+            Some(creator) => {
+                let account = self.clients.get(&client_id)
+                    .and_then(|c| c.account.clone());
+                account.map(|a| username::key(&a[..])) ==
+                    Some(username::key(&creator[..]))
+            },
+            None => room_name != LOBBY,
+        }
+    }
+
+    /// Changes a room's topic or description, or both, for a client that
+    /// may, and tells the room. An empty text clears the topic or
+    /// description.
+    fn change_room_info(&mut self, room_name: &str, client_id: i64,
+                        topic: Option<String>, description: Option<String>,
+                        request_id: Option<String>) {
+        let error = if !self.is_room_member(room_name, client_id) {
+            Some((ERR_NOT_IN_ROOM, format!("you aren't in {}", room_name)))
+        } else if !self.may_edit_room(room_name, client_id) {
+            Some((ERR_FORBIDDEN,
+                  String::from("only the room's creator or a moderator may \
+                                change its topic")))
+        } else if topic.as_ref()
+                .map_or(false, |t| t.chars().count() > MAX_TOPIC_LEN) {
+            Some((ERR_BAD_REQUEST,
+                  format!("topics can be at most {} characters",
+                          MAX_TOPIC_LEN)))
+        } else if description.as_ref()
+                .map_or(false, |d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+            Some((ERR_BAD_REQUEST,
+                  format!("descriptions can be at most {} characters",
+                          MAX_DESCRIPTION_LEN)))
+        } else {
+            None
+        };
+        if let Some((code, text)) = error {
+            let msg = ServerMessage::error(code, text, request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+
+        let record = match self.rooms.get_mut(room_name) {
+            Some(room) => {
+                let non_empty = |text: String| {
+                    let text = String::from(text.trim());
+                    if text.len() > 0 {
+                        Some(text)
+                    } else {
+                        None
+                    }
+                };
+                if let Some(topic) = topic {
+                    room.record.topic = non_empty(topic);
+                }
+                if let Some(description) = description {
+                    room.record.description = non_empty(description);
+                }
+                room.record.clone()
+            },
+            None => return,
+        };
+        if let Err(e) = self.storage.save_room(&record) {
+            println!("failed to store room {}: {}", room_name, e);
+        }
+
+        let (id, timestamp) = self.stamp();
+        let msg = ServerMessage::TopicChanged{
+            room: String::from(room_name),
+            topic: record.topic,
+            description: record.description,
+            client_id: client_id,
+            id: id,
+            timestamp: timestamp,
+        };
+        self.dispatch_room_message(room_name, msg);
+    }
+
+    pub fn leave_room(&mut self, room_name: &str, client_id: i64) {
+        if self.typing.stop(room_name, client_id) {
+            self.announce_typing_stopped(room_name, client_id);
+        }
+        let was_member = match self.rooms.get_mut(room_name) {
+            Some(room) => room.members.remove(&client_id),
+            None => false,
+        };
+        if !was_member {
+            return;
+        }
+
+        let (id, timestamp) = self.stamp();
+        let left_msg = ServerMessage::RoomLeft{
+            room: String::from(room_name),
+            client_id: client_id,
+            id: id,
+            timestamp: timestamp,
+        };
+        // the leaver is no longer a member, so tell them separately
+        self.send_to(&vec![client_id], left_msg.clone());
+        self.dispatch_room_message(room_name, left_msg);
+    }
+
+    fn room_list(&self) -> Vec<RoomSummary> {
+        let mut rooms: Vec<RoomSummary> = self.rooms.iter()
+            .map(|(name, room)| {
+                RoomSummary {
+                    name: name.clone(),
+                    members: room.members.len(),
+                }
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// Looks up a connected client by client_id, falling back to username.
+    ///
+    /// Ids come first: names may be all digits, and a client mustn't be
+    /// able to intercept messages by taking another's id as its name.
+    fn find_client(&self, name_or_id: &str) -> Option<i64> {
+        match name_or_id.parse::<i64>() {
+            Ok(client_id) if self.clients.contains_key(&client_id) => {
+                return Some(client_id);
+            },
+            _ => (),
+        }
+
+        let key = username::key(name_or_id);
+        self.clients.values()
+            .filter(|c| c.name.as_ref().map(|n| username::key(&n[..])) ==
+                    Some(key.clone()))
+            .map(|c| c.client_id)
+            .next()
+    }
+
+    fn is_room_member(&self, room_name: &str, client_id: i64) -> bool {
+        match self.rooms.get(room_name) {
+            Some(room) => room.members.contains(&client_id),
+            None => false,
+        }
+    }
+
+    /// Validates a username requested by a client, returning the normalized
+    /// name if the client may take it, or the reply explaining why not.
+    ///
+    /// The client's own current name (in any case) and its account's name
+    /// don't count as taken.
+    fn check_username(&self, name: &str, client_id: i64,
+                      request_id: &Option<String>)
+                      -> Result<String, ServerMessage> {
+        let name = match username::normalize(name) {
+            Ok(name) => name,
+            Err(e) => {
+                return Err(ServerMessage::error(
+                    ERR_INVALID_USERNAME, e.to_string(), request_id.clone()));
+            },
+        };
+
+        if let Some(ban) = self.find_ban(&name[..]) {
+            return Err(ServerMessage::error(
+                ERR_USERNAME_BANNED,
+                format!("{} is banned: {}", name, ban.reason),
+                request_id.clone()));
+        }
+
+        let key = username::key(&name[..]);
+        let (own_name, own_account) = match self.clients.get(&client_id) {
+            Some(client) => (
+                client.name.as_ref().map(|n| username::key(&n[..])),
+                client.account.as_ref().map(|a| username::key(&a[..]))),
+            None => (None, None),
+        };
+        if own_account.as_ref() != Some(&key)
+            && self.storage.account(&name[..]).is_some() {
+            // case: name belongs to an account; must log in instead
+            return Err(ServerMessage::error(
+                ERR_USERNAME_RESERVED,
+                format!("{} belongs to an account; log in to use it", name),
+                request_id.clone()));
+        }
+        if own_name.as_ref() != Some(&key)
+            && self.client_usernames.contains(&key) {
+            return Err(ServerMessage::error(
+                ERR_USERNAME_IN_USE, format!("{} is already in use", name),
+                request_id.clone()));
+        }
+        Ok(name)
+    }
+
+    /// Gives a client a new name, releasing its old one, and tells everyone.
+    fn set_username(&mut self, client_id: i64, name: String) {
+        let old_name = match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                if client.name.as_ref() == Some(&name) {
+                    return;
+                }
+                let old_name = client.name.take();
+                client.name = Some(name.clone());
+                old_name
+            },
+            None => return,
+        };
+
+        if let Some(ref old_name) = old_name {
+            self.client_usernames.remove(&username::key(&old_name[..]));
+        }
+        self.client_usernames.insert(username::key(&name[..]));
+        println!("Client {} is now called {}", client_id, name);
+
+        let (id, timestamp) = self.stamp();
+        let msg = match old_name {
+            Some(old_name) => ServerMessage::UsernameChanged{
+                old: old_name, new: name, client_id: client_id,
+                id: id, timestamp: timestamp},
+            None => ServerMessage::UsernameRegistration{
+                name: name, client_id: client_id,
+                id: id, timestamp: timestamp},
+        };
+        self.dispatch_message(msg);
+    }
+
+    fn find_ban(&self, name: &str) -> Option<Ban> {
+        let key = username::key(name);
+        self.storage.bans().into_iter()
+            .filter(|b| username::key(&b.name[..]) == key)
+            .next()
+    }
+
+    /// Bans a username, and hangs up on every client using it or logged in
+    /// to the account of that name.
+    fn ban(&mut self, name: String, reason: String) {
+        let ban = Ban {
+            name: name,
+            reason: reason,
+        };
+        if let Err(e) = self.storage.add_ban(&ban) {
+            println!("failed to store ban: {}", e);
+        }
+
+        let key = Some(username::key(&ban.name[..]));
+        let banned: Vec<ChatClient> = self.clients.values()
+            .filter(|c| c.name.as_ref().map(|n| username::key(&n[..])) == key
+                    || c.account.as_ref().map(|a| username::key(&a[..])) == key)
+            .cloned()
+            .collect();
+        for client in banned.iter() {
+            println!("Client {} is banned as {}", client.client_id, ban.name);
+            for conn in client.connections.iter() {
+                conn.queue.close(CLOSE_POLICY_VIOLATION);
+                self.forget_connection(conn.conn_id);
+            }
+            self.end_session(client);
+        }
+    }
+
+    pub fn dispatch_message(&mut self, msg: ServerMessage) {
+        println!("client msg: {:?}", msg);
+        let client_ids: Vec<i64> = self.clients.keys().cloned().collect();
+        self.send_to(&client_ids, msg);
+    }
+
+    /// Sends a message to the current members of a room.
+    pub fn dispatch_room_message(&mut self, room_name: &str,
+                                 msg: ServerMessage) {
+        println!("room msg ({}): {:?}", room_name, msg);
+        let client_ids: Vec<i64> = match self.rooms.get(room_name) {
+            Some(room) => room.members.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        self.send_to(&client_ids, msg);
+    }
+
+    /// Sends a message to every connection of the given clients.
+    fn send_to(&mut self, client_ids: &Vec<i64>, msg: ServerMessage) {
+        let mut overflowed = Vec::new();
+        for client_id in client_ids.iter() {
+            match self.clients.get(client_id) {
+                Some(client) => {
+                    for conn in client.connections.iter() {
+                        if !conn.queue.push(msg.clone()) {
+                            overflowed.push((client.client_id, conn.conn_id));
+                        }
+                    }
+                },
+                None => (),
+            }
+        }
+
+        for (client_id, conn_id) in overflowed {
+            // case: connection can't keep up; its writer thread hangs up on
+            // it
+            println!("connection {} of client {} overflowed its queue",
+                     conn_id, client_id);
+            self.remove_connection(client_id, conn_id);
+        }
+    }
+
+    fn stats(&self) -> Vec<ClientStats> {
+        let mut stats = Vec::new();
+        for client in self.clients.values() {
+            for conn in client.connections.iter() {
+                stats.push(ClientStats {
+                    client_id: client.client_id,
+                    conn_id: conn.conn_id,
+                    queue_depth: conn.queue.depth(),
+                    dropped: conn.queue.dropped(),
+                });
+            }
+        }
+        stats
+    }
+
+    pub fn handle_client_msg(&mut self, mut msg: ClientMessage,
+                             client_id: i64, request_id: Option<String>) {
+        // typing notices are frequent, and throttled separately
+        let limited = match msg {
+            ClientMessage::TypingStarted{..} => false,
+            ClientMessage::TypingStopped{..} => false,
+            _ => true,
+        };
+        let now = now_millis();
+        let (allowed, was_idle) = match self.clients.get_mut(&client_id) {
+            Some(client) => {
+                client.last_active = now;
+                let was_idle = client.auto_away;
+                if was_idle {
+                    client.presence = Presence::Online;
+                    client.auto_away = false;
+                }
+                (!limited || client.rate_limiter.allow(now), was_idle)
+            },
+            None => return,
+        };
+        if was_idle {
+            // case: client is back from being idle
+            self.announce_presence(client_id);
+        }
+        if !allowed {
+            let msg = ServerMessage::error(
+                ERR_RATE_LIMITED, String::from("slow down"), request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+
+        // rooms are only ever known by their normalized names
+        if let Some(room) = msg.room_mut() {
+            match roomname::normalize(&room[..]) {
+                Ok(name) => *room = name,
+                Err(e) => {
+                    let msg = ServerMessage::error(
+                        ERR_BAD_REQUEST, e.to_string(), request_id);
+                    self.send_to(&vec![client_id], msg);
+                    return;
+                },
+            }
+        }
+
+        match msg {
+            ClientMessage::TextMessage{message: message,
+                                       parent_id: parent_id} => {
+                if let Some((name, args)) = commands::parse(&message[..]) {
+                    self.run_command(LOBBY, name, args, client_id, request_id);
+                    return;
+                }
+                let message = commands::unescape(message);
+                self.send_text(LOBBY, client_id, message, parent_id,
+                               request_id);
+                return;
+            },
+            ClientMessage::RoomTextMessage{room: room, message: message,
+                                           parent_id: parent_id} => {
+                if let Some((name, args)) = commands::parse(&message[..]) {
+                    self.run_command(&room[..], name, args, client_id,
+                                     request_id);
+                    return;
+                }
+                let message = commands::unescape(message);
+                self.send_text(&room[..], client_id, message, parent_id,
+                               request_id);
+                return;
+            },
+            ClientMessage::JoinRoom{room: room} => {
+                self.join_room(&room[..], client_id);
+                return;
+            },
+            ClientMessage::LeaveRoom{room: room} => {
+                self.leave_room(&room[..], client_id);
+                return;
+            },
+            ClientMessage::ListRooms => {
+                let msg = ServerMessage::RoomList{rooms: self.room_list()};
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            ClientMessage::FetchHistory{room: room, before: before,
+                                        limit: limit} => {
+                let msg = if self.is_room_member(&room[..], client_id) {
+                    let limit = cmp::min(limit, self.config.history_len);
+                    let messages = self.history.fetch(&*self.storage,
+                                                      &room[..], before, limit);
+                    ServerMessage::History{
+                        threads: self.thread_summaries(&messages),
+                        reactions: self.reaction_summaries(&messages),
+                        messages: messages,
+                        room: room,
+                    }
+                } else {
+                    ServerMessage::error(ERR_NOT_IN_ROOM,
+                                         format!("you aren't in {}", room),
+                                         request_id)
+                };
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            ClientMessage::DirectMessage{to: to, message: message} => {
+                self.direct_message(client_id, &to[..], message, request_id);
+                return;
+            },
+            ClientMessage::RegisterAccount{name: name, password: password} => {
+                self.register_account(client_id, request_id, name, password);
+                return;
+            },
+            ClientMessage::Login{name: name, password: password} => {
+                // log in under the account's own spelling of the name
+                let account = self.storage.account(&name[..]);
+                let name = account.as_ref().map_or(name, |a| a.name.clone());
+                let password_hash = account.map(|a| a.password_hash);
+                self.queue_hash_job(HashJob::Verify{
+                    client_id: client_id, request_id: request_id, name: name,
+                    password: password, password_hash: password_hash});
+                return;
+            },
+            ClientMessage::EditMessage{id: message_id, message: message} => {
+                if message.trim().len() == 0 {
+                    let msg = ServerMessage::error(
+                        ERR_BAD_REQUEST,
+                        String::from("messages can't be empty; delete it \
+                                      instead"),
+                        request_id);
+                    self.send_to(&vec![client_id], msg);
+                    return;
+                }
+                let room = match self.check_author(message_id, client_id) {
+                    Ok(room) => room,
+                    Err((code, text)) => {
+                        let msg = ServerMessage::error(code, text, request_id);
+                        self.send_to(&vec![client_id], msg);
+                        return;
+                    },
+                };
+
+                let previous = match self.storage.message(message_id) {
+                    Some((_, ServerMessage::TextMessage{message, ..})) => {
+                        message
+                    },
+                    _ => String::new(),
+                };
+                let (id, timestamp) = self.stamp();
+                let edit = MessageEdit {
+                    message: message.clone(),
+                    previous: previous,
+                    editor: client_id,
+                    timestamp: timestamp,
+                };
+                if let Err(e) = self.storage.edit_message(message_id, &edit) {
+                    println!("failed to store edit: {}", e);
+                }
+                self.history.edit(message_id, message.clone());
+                let edited_msg = ServerMessage::MessageEdited{
+                    message_id: message_id,
+                    room: room.clone(),
+                    message: message,
+                    client_id: client_id,
+                    id: id,
+                    timestamp: timestamp,
+                };
+                self.dispatch_room_message(&room[..], edited_msg);
+                return;
+            },
+            ClientMessage::DeleteMessage{id: message_id} => {
+                let room = match self.check_author(message_id, client_id) {
+                    Ok(room) => room,
+                    Err((code, text)) => {
+                        let msg = ServerMessage::error(code, text, request_id);
+                        self.send_to(&vec![client_id], msg);
+                        return;
+                    },
+                };
+                let parent_id = self.storage.message(message_id)
+                    .and_then(|(_, msg)| msg.parent_id());
+
+                if let Err(e) = self.storage.delete_message(message_id) {
+                    println!("failed to store deletion: {}", e);
+                }
+                self.history.remove(message_id);
+                self.reactions.forget_message(message_id);
+                let (id, timestamp) = self.stamp();
+                let deleted_msg = ServerMessage::MessageDeleted{
+                    message_id: message_id,
+                    room: room.clone(),
+                    client_id: client_id,
+                    id: id,
+                    timestamp: timestamp,
+                };
+                self.dispatch_room_message(&room[..], deleted_msg);
+                if let Some(parent_id) = parent_id {
+                    self.announce_thread(&room[..], parent_id);
+                }
+                return;
+            },
+            ClientMessage::FetchEdits{id: message_id} => {
+                let msg = match self.storage.message(message_id) {
+                    Some((ref room, _))
+                        if self.is_room_member(&room[..], client_id) => {
+                        ServerMessage::MessageEdits{
+                            message_id: message_id,
+                            edits: self.storage.message_edits(message_id),
+                        }
+                    },
+                    // case: no peeking at rooms the client isn't in
+                    _ => ServerMessage::error(
+                        ERR_NO_SUCH_MESSAGE,
+                        format!("no message has id {}", message_id),
+                        request_id),
+                };
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            ClientMessage::FetchThread{id: message_id} => {
+                let msg = match self.thread_of(message_id, client_id) {
+                    Ok((room, parent_id)) => {
+                        let mut messages: Vec<ServerMessage> =
+                            self.storage.message(parent_id)
+                                .map(|(_, parent)| parent)
+                                .into_iter()
+                                .collect();
+                        messages.extend(self.storage.replies(parent_id));
+                        ServerMessage::Thread{
+                            parent_id: parent_id,
+                            room: room,
+                            messages: messages,
+                        }
+                    },
+                    Err(text) => ServerMessage::error(ERR_NO_SUCH_MESSAGE, text,
+                                                      request_id),
+                };
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            ClientMessage::AddReaction{message_id: message_id,
+                                       emoji: emoji} => {
+                let emoji = String::from(emoji.trim());
+                if emoji.len() == 0
+                    || emoji.chars().count() > reactions::MAX_EMOJI_LEN
+                    || emoji.chars().any(|c| c.is_whitespace()) {
+                    let msg = ServerMessage::error(
+                        ERR_BAD_REQUEST,
+                        format!("reactions must be one emoji or shortcode of \
+                                 at most {} characters",
+                                reactions::MAX_EMOJI_LEN),
+                        request_id);
+                    self.send_to(&vec![client_id], msg);
+                    return;
+                }
+                let room = match self.thread_of(message_id, client_id) {
+                    Ok((room, _)) => room,
+                    Err(text) => {
+                        let msg = ServerMessage::error(ERR_NO_SUCH_MESSAGE,
+                                                       text, request_id);
+                        self.send_to(&vec![client_id], msg);
+                        return;
+                    },
+                };
+
+                if self.reactions.add(message_id, &emoji[..], client_id) {
+                    let reaction = StoredReaction {
+                        message_id: message_id,
+                        emoji: emoji,
+                        client_id: client_id,
+                    };
+                    if let Err(e) = self.storage.add_reaction(&reaction) {
+                        println!("failed to store reaction: {}", e);
+                    }
+                    self.announce_reactions(&room[..], message_id);
+                }
+                return;
+            },
+            ClientMessage::RemoveReaction{message_id: message_id,
+                                          emoji: emoji} => {
+                let room = match self.thread_of(message_id, client_id) {
+                    Ok((room, _)) => room,
+                    Err(text) => {
+                        let msg = ServerMessage::error(ERR_NO_SUCH_MESSAGE,
+                                                       text, request_id);
+                        self.send_to(&vec![client_id], msg);
+                        return;
+                    },
+                };
+
+                let emoji = String::from(emoji.trim());
+                if self.reactions.remove(message_id, &emoji[..], client_id) {
+                    let reaction = StoredReaction {
+                        message_id: message_id,
+                        emoji: emoji,
+                        client_id: client_id,
+                    };
+                    if let Err(e) = self.storage.remove_reaction(&reaction) {
+                        println!("failed to store reaction removal: {}", e);
+                    }
+                    self.announce_reactions(&room[..], message_id);
+                }
+                return;
+            },
+            ClientMessage::SetTopic{room: room, topic: topic} => {
+                self.change_room_info(&room[..], client_id, Some(topic), None,
+                                      request_id);
+                return;
+            },
+            ClientMessage::SetDescription{room: room,
+                                          description: description} => {
+                self.change_room_info(&room[..], client_id, None,
+                                      Some(description), request_id);
+                return;
+            },
+            ClientMessage::SetPresence{presence: presence, status: status} => {
+                let status = status.map(|s| String::from(s.trim()))
+                    .filter(|s| s.len() > 0);
+                let too_long = status.as_ref()
+                    .map_or(false, |s| s.chars().count() > MAX_STATUS_LEN);
+                if too_long {
+                    let msg = ServerMessage::error(
+                        ERR_BAD_REQUEST,
+                        format!("status can be at most {} characters",
+                                MAX_STATUS_LEN),
+                        request_id);
+                    self.send_to(&vec![client_id], msg);
+                    return;
+                }
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.presence = presence;
+                    client.status = status;
+                    client.auto_away = false;
+                }
+                self.announce_presence(client_id);
+                return;
+            },
+            ClientMessage::TypingStarted{room: room} => {
+                if !self.is_room_member(&room[..], client_id) {
+                    let msg = ServerMessage::error(
+                        ERR_NOT_IN_ROOM, format!("you aren't in {}", room),
+                        request_id);
+                    self.send_to(&vec![client_id], msg);
+                } else if self.typing.start(&room[..], client_id,
+                                            now_millis()) {
+                    let msg = ServerMessage::TypingStarted{
+                        room: room.clone(),
+                        client_id: client_id,
+                    };
+                    self.send_to_room_except(&room[..], client_id, msg);
+                }
+                return;
+            },
+            ClientMessage::TypingStopped{room: room} => {
+                if self.typing.stop(&room[..], client_id) {
+                    self.announce_typing_stopped(&room[..], client_id);
+                }
+                return;
+            },
+            ClientMessage::Unknown => {
+                let msg = ServerMessage::error(
+                    ERR_UNKNOWN_COMMAND, String::from("unknown command"),
+                    request_id);
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            ClientMessage::UsernameRegistration{name: name} => {
+                match self.check_username(&name[..], client_id, &request_id) {
+                    Ok(name) => self.set_username(client_id, name),
+                    Err(msg) => {
+                        // case: username is invalid, banned, reserved or in
+                        // use
+                        self.send_to(&vec![client_id], msg);
+                    },
+                }
+                return;
+            }
+        }
+    }
+
+    fn direct_message(&mut self, client_id: i64, to: &str, message: String,
+                      request_id: Option<String>) {
+        match self.find_client(to) {
+            Some(recipient_id) => {
+                let (id, timestamp) = self.stamp();
+                let msg = ServerMessage::DirectMessage{
+                    message: message,
+                    from: client_id,
+                    to: recipient_id,
+                    id: id,
+                    timestamp: timestamp,
+                };
+                let mut client_ids = vec![recipient_id];
+                if recipient_id != client_id {
+                    // echo back to the sender
+                    client_ids.push(client_id);
+                }
+                self.send_to(&client_ids, msg);
+            },
+            None => {
+                let msg = ServerMessage::error(
+                    ERR_RECIPIENT_UNAVAILABLE,
+                    format!("{} isn't connected", to), request_id);
+                self.send_to(&vec![client_id], msg);
+            },
+        }
+    }
+
+    /// Runs the slash command a client typed in a room.
+    fn run_command(&mut self, room_name: &str, name: &str, args: &str,
+                   client_id: i64, request_id: Option<String>) {
+        let parsed = match self.commands.get(name) {
+            Some(command) => {
+                command.parse_args(args)
+                    .map(|args| (command.handler, args))
+                    .map_err(|usage| (ERR_BAD_REQUEST, usage))
+            },
+            None => Err((ERR_UNKNOWN_COMMAND,
+                         format!("unknown command /{}; try /help", name))),
+        };
+        let result = match parsed {
+            Ok((handler, args)) => {
+                let invocation = Invocation {
+                    client_id: client_id,
+                    room: String::from(room_name),
+                    args: args,
+                    request_id: request_id.clone(),
+                };
+                handler(self, &invocation)
+            },
+            Err(e) => Err(e),
+        };
+        if let Err((code, text)) = result {
+            let msg = ServerMessage::error(code, text, request_id);
+            self.send_to(&vec![client_id], msg);
+        }
+    }
+
+    /// Sends a client text from the server, e.g. a command's output.
+    pub fn notify(&mut self, client_id: i64, text: String,
+                  request_id: Option<String>) {
+        let msg = ServerMessage::Notice{
+            message: text,
+            request_id: request_id,
+        };
+        self.send_to(&vec![client_id], msg);
+    }
+
+    /// Sends a text message to a room on a client's behalf, as a reply if
+    /// it answers the message `parent_id`.
+    pub fn send_text(&mut self, room_name: &str, client_id: i64, text: String,
+                     parent_id: Option<u64>, request_id: Option<String>) {
+        let parent_id = match parent_id {
+            Some(parent_id) => match self.thread_of(parent_id, client_id) {
+                Ok((ref room, thread)) if &room[..] == room_name => {
+                    Some(thread)
+                },
+                _ => {
+                    let msg = ServerMessage::error(
+                        ERR_NO_SUCH_MESSAGE,
+                        format!("{} has no message with id {}", room_name,
+                                parent_id),
+                        request_id);
+                    self.send_to(&vec![client_id], msg);
+                    return;
+                },
+            },
+            None => None,
+        };
+
+        let (id, timestamp) = self.stamp();
+        let msg = ServerMessage::TextMessage{
+            mentions: self.resolve_mentions(room_name, &text[..], client_id),
+            message: text,
+            client_id: client_id,
+            room: String::from(room_name),
+            id: id,
+            timestamp: timestamp,
+            parent_id: parent_id,
+        };
+        self.room_text_message(room_name, msg, client_id, request_id);
+        if let Some(parent_id) = parent_id {
+            self.announce_thread(room_name, parent_id);
+        }
+    }
+
+    /// A client's name, or `#<client_id>` if it hasn't got one.
+    pub fn display_name(&self, client_id: i64) -> String {
+        match self.clients.get(&client_id) {
+            Some(&ChatClient{name: Some(ref name), ..}) => name.clone(),
+            _ => format!("#{}", client_id),
+        }
+    }
+
+    fn register_account(&mut self, client_id: i64, request_id: Option<String>,
+                        name: String, password: String) {
+        let name = match username::normalize(&name[..]) {
+            Ok(name) => name,
+            Err(e) => {
+                let msg = ServerMessage::error(ERR_INVALID_USERNAME,
+                                               e.to_string(), request_id);
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+        };
+
+        let rejection = if let Some(ban) = self.find_ban(&name[..]) {
+            Some((ERR_USERNAME_BANNED,
+                  format!("{} is banned: {}", name, ban.reason)))
+        } else if self.storage.account(&name[..]).is_some() {
+            Some((ERR_ACCOUNT_EXISTS,
+                  format!("an account named {} already exists", name)))
+        } else if self.is_name_taken(&name[..], client_id) {
+            Some((ERR_USERNAME_IN_USE, format!("{} is already in use", name)))
+        } else if password.chars().count() < self.config.min_password_len {
+            Some((ERR_PASSWORD_REJECTED,
+                  format!("passwords must be at least {} characters",
+                          self.config.min_password_len)))
+        } else {
+            None
+        };
+        if let Some((code, message)) = rejection {
+            let msg = ServerMessage::error(code, message, request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+
+        self.queue_hash_job(HashJob::Hash{
+            client_id: client_id, request_id: request_id, name: name,
+            password: password});
+    }
+
+    /// Hands password work to the hashing thread, or tells the client to try
+    /// again later if too much is already waiting.
+    fn queue_hash_job(&mut self, job: HashJob) {
+        let (client_id, request_id) = match self.hash_jobs.try_send(job) {
+            Ok(()) => return,
+            Err(TrySendError::Full(HashJob::Hash{client_id, request_id, ..}))
+                | Err(TrySendError::Full(HashJob::Verify{client_id,
+                                                         request_id, ..})) => {
+                (client_id, request_id)
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                println!("the password hashing thread has stopped");
+                return;
+            },
+        };
+        let msg = ServerMessage::error(
+            ERR_RATE_LIMITED,
+            String::from("the server is busy; try again shortly"),
+            request_id);
+        self.send_to(&vec![client_id], msg);
+    }
+
+    fn create_account(&mut self, client_id: i64, request_id: Option<String>,
+                      name: String, password_hash: String) {
+        // the name may have been claimed while the password was hashed
+        if self.storage.account(&name[..]).is_some() {
+            let msg = ServerMessage::error(
+                ERR_ACCOUNT_EXISTS,
+                format!("an account named {} already exists", name),
+                request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+        if self.is_name_taken(&name[..], client_id) {
+            let msg = ServerMessage::error(
+                ERR_USERNAME_IN_USE, format!("{} is already in use", name),
+                request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+
+        let account = Account {
+            name: name.clone(),
+            password_hash: password_hash,
+        };
+        if let Err(e) = self.storage.save_account(&account) {
+            println!("failed to store account {}: {}", name, e);
+            return;
+        }
+        println!("account created: {}", name);
+        self.send_to(&vec![client_id],
+                     ServerMessage::AccountCreated{name: name.clone()});
+        self.log_in(client_id, name, request_id);
+    }
+
+    fn finish_login(&mut self, client_id: i64, request_id: Option<String>,
+                    name: String, ok: bool) {
+        if !self.clients.contains_key(&client_id) {
+            // case: client hung up while its password was checked
+            return;
+        }
+        if !ok {
+            let msg = ServerMessage::error(
+                ERR_LOGIN_FAILED, String::from("wrong name or password"),
+                request_id);
+            self.send_to(&vec![client_id], msg);
+        } else {
+            self.log_in(client_id, name, request_id);
+        }
+    }
+
+    /// Binds a client to an account, taking the account's name as its
+    /// username. If another client is already logged in to the account, the
+    /// client's connections join that one instead.
+    fn log_in(&mut self, client_id: i64, name: String,
+              request_id: Option<String>) {
+        if let Some(ban) = self.find_ban(&name[..]) {
+            let msg = ServerMessage::error(
+                ERR_USERNAME_BANNED,
+                format!("{} is banned: {}", name, ban.reason), request_id);
+            self.send_to(&vec![client_id], msg);
+            return;
+        }
+
+        let key = username::key(&name[..]);
+        let holder = self.clients.values()
+            .filter(|c| c.client_id != client_id
+                    && c.name.as_ref().map(|n| username::key(&n[..])) ==
+                       Some(key.clone()))
+            .map(|c| (c.client_id, c.account.clone()))
+            .next();
+        match holder {
+            Some((holder_id, Some(ref account)))
+                if username::key(&account[..]) == key => {
+                self.merge_client(client_id, holder_id);
+                return;
+            },
+            Some(_) => {
+                let msg = ServerMessage::error(
+                    ERR_USERNAME_IN_USE, format!("{} is already in use", name),
+                    request_id);
+                self.send_to(&vec![client_id], msg);
+                return;
+            },
+            None => (),
+        }
+
+        match self.clients.get_mut(&client_id) {
+            Some(client) => client.account = Some(name.clone()),
+            None => return,
+        }
+        println!("Client {} logged in as {}", client_id, name);
+        self.set_username(client_id, name);
+    }
+
+    /// Whether a username is held by a client other than `client_id`.
+    fn is_name_taken(&self, name: &str, client_id: i64) -> bool {
+        self.clients.values()
+            .any(|c| c.client_id != client_id
+                 && c.name.as_ref().map(|n| username::key(&n[..])) ==
+                    Some(username::key(name)))
+    }
+
+    /// Checks that a client may change a room message: it must have sent it,
+    /// or be a moderator. Returns the message's room, or the error code and
+    /// text to send back.
+    fn check_author(&self, message_id: u64, client_id: i64)
+                    -> Result<String, (&'static str, String)> {
+        let (room, author) = match self.storage.message(message_id) {
+            Some((room, ServerMessage::TextMessage{client_id: author, ..})) => {
+                (room, author)
+            },
+            _ => {
+                return Err((ERR_NO_SUCH_MESSAGE,
+                            format!("no message has id {}", message_id)));
+            },
+        };
+        if author != client_id && !self.is_moderator(client_id) {
+            return Err((ERR_FORBIDDEN,
+                        String::from("only the sender or a moderator may \
+                                      change a message")));
+        }
+        Ok(room)
+    }
+
+    /// Finds the thread a room message belongs to: its own, or its parent's
+    /// if it is a reply. Returns the room and the thread's first message, or
+    /// the error text to send back.
+    fn thread_of(&self, message_id: u64, client_id: i64)
+                 -> Result<(String, u64), String> {
+        let thread = match self.storage.message(message_id) {
+            Some((room, ServerMessage::TextMessage{parent_id, ..})) => {
+                Some((room, parent_id.unwrap_or(message_id)))
+            },
+            _ => None,
+        };
+        match thread {
+            // case: no peeking at rooms the client isn't in
+            Some((room, parent_id))
+                if self.is_room_member(&room[..], client_id) => {
+                Ok((room, parent_id))
+            },
+            _ => Err(format!("no message has id {}", message_id)),
+        }
+    }
+
+    /// Reply counts of the messages in a page of history which have any.
+    fn thread_summaries(&self, messages: &Vec<ServerMessage>)
+                        -> Vec<ThreadSummary> {
+        messages.iter()
+            .filter_map(|m| m.id())
+            .map(|id| ThreadSummary {
+                parent_id: id,
+                reply_count: self.storage.reply_count(id),
+            })
+            .filter(|thread| thread.reply_count > 0)
+            .collect()
+    }
+
+    /// Reactions to the messages in a page of history which have any.
+    fn reaction_summaries(&self, messages: &Vec<ServerMessage>)
+                          -> Vec<MessageReactions> {
+        messages.iter()
+            .filter_map(|m| m.id())
+            .map(|id| MessageReactions {
+                message_id: id,
+                reactions: self.reactions.of(id),
+            })
+            .filter(|m| m.reactions.len() > 0)
+            .collect()
+    }
+
+    /// Tells a room everyone's reactions to one of its messages.
+    fn announce_reactions(&mut self, room_name: &str, message_id: u64) {
+        let msg = ServerMessage::ReactionsUpdated{
+            message_id: message_id,
+            room: String::from(room_name),
+            reactions: self.reactions.of(message_id),
+        };
+        self.dispatch_room_message(room_name, msg);
+    }
+
+    /// Tells a room how many replies a message now has.
+    fn announce_thread(&mut self, room_name: &str, parent_id: u64) {
+        let msg = ServerMessage::ThreadUpdated{
+            parent_id: parent_id,
+            room: String::from(room_name),
+            reply_count: self.storage.reply_count(parent_id),
+        };
+        self.dispatch_room_message(room_name, msg);
+    }
+
+    /// Whether a client is logged in to a moderator's account.
+    fn is_moderator(&self, client_id: i64) -> bool {
+        let account = match self.clients.get(&client_id) {
+            Some(&ChatClient{account: Some(ref account), ..}) => {
+                username::key(&account[..])
+            },
+            _ => return false,
+        };
+        self.config.moderators.iter()
+            .any(|m| username::key(&m[..]) == account)
+    }
+
+    /// Tells a room, other than the client itself, that a client stopped
+    /// typing.
+    fn announce_typing_stopped(&mut self, room_name: &str, client_id: i64) {
+        let msg = ServerMessage::TypingStopped{
+            room: String::from(room_name),
+            client_id: client_id,
+        };
+        self.send_to_room_except(room_name, client_id, msg);
+    }
+
+    /// Resolves the `@name`, `@here` and `@room` mentions in a room message
+    /// to client_ids, leaving out the sender. `@room` is every member of
+    /// the room and `@here` every member who is online.
+    fn resolve_mentions(&self, room_name: &str, text: &str, sender: i64)
+                        -> Vec<i64> {
+        let members: Vec<i64> = match self.rooms.get(room_name) {
+            Some(room) => room.members.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+
+        // keys are skeletons, which needn't read as the names themselves
+        let room_key = username::key("room");
+        let here_key = username::key("here");
+        let mut client_ids = Vec::new();
+        for name in username::mentions(text) {
+            let key = username::key(&name[..]);
+            if key == room_key {
+                client_ids.extend(members.iter().cloned());
+            } else if key == here_key {
+                client_ids.extend(members.iter().cloned().filter(|id| {
+                    self.clients.get(id)
+                        .map_or(false, |c| c.presence == Presence::Online)
+                }));
+            } else {
+                client_ids.extend(self.clients.values()
+                    .filter(|c| c.name.as_ref()
+                            .map(|n| username::key(&n[..])) ==
+                            Some(key.clone()))
+                    .map(|c| c.client_id));
+            }
+        }
+        client_ids.retain(|id| *id != sender);
+        client_ids.sort();
+        client_ids.dedup();
+        client_ids
+    }
+
+    /// Sends `Mentioned` to everyone a room message mentions, except those
+    /// who don't want to be disturbed.
+    fn notify_mentioned(&mut self, msg: &ServerMessage) {
+        let (notice, mentions) = match *msg {
+            ServerMessage::TextMessage{ref message, client_id, ref room, id,
+                                       ref mentions, ..} => {
+                let notice = ServerMessage::Mentioned{
+                    message_id: id,
+                    room: room.clone(),
+                    message: message.clone(),
+                    client_id: client_id,
+                };
+                (notice, mentions.clone())
+            },
+            _ => return,
+        };
+        let client_ids: Vec<i64> = mentions.into_iter()
+            .filter(|id| {
+                self.clients.get(id)
+                    .map_or(false, |c| c.presence != Presence::DoNotDisturb)
+            })
+            .collect();
+        if client_ids.len() > 0 {
+            self.send_to(&client_ids, notice);
+        }
+    }
+
+    fn send_to_room_except(&mut self, room_name: &str, client_id: i64,
+                           msg: ServerMessage) {
+        let client_ids: Vec<i64> = match self.rooms.get(room_name) {
+            Some(room) => room.members.iter()
+                .cloned()
+                .filter(|id| *id != client_id)
+                .collect(),
+            None => Vec::new(),
+        };
+        self.send_to(&client_ids, msg);
+    }
+
+    fn room_text_message(&mut self, room_name: &str, msg: ServerMessage,
+                         client_id: i64, request_id: Option<String>) {
+        if self.is_room_member(room_name, client_id) {
+            // sending a message ends composing it
+            if self.typing.stop(room_name, client_id) {
+                self.announce_typing_stopped(room_name, client_id);
+            }
+            self.history.record(room_name, msg.clone());
+            if let Err(e) = self.storage.append_message(room_name, &msg) {
+                println!("failed to store message: {}", e);
+            }
+            self.notify_mentioned(&msg);
+            self.dispatch_room_message(room_name, msg);
+        } else {
+            let msg = ServerMessage::error(
+                ERR_NOT_IN_ROOM, format!("you aren't in {}", room_name),
+                request_id);
+            self.send_to(&vec![client_id], msg);
+        }
+    }
+}
+
+/// The slash commands every server knows.
+pub fn builtin_commands() -> Commands {
+    let mut commands = Commands::new();
+    commands.register(Command {
+        name: "ban",
+        usage: "<name> [reason]",
+        help: "bans a username and hangs up on whoever uses it (moderators \
+               only)",
+        min_args: 1,
+        max_args: 2,
+        handler: ban_command,
+    });
+    commands.register(Command {
+        name: "help",
+        usage: "[command]",
+        help: "lists commands, or explains one",
+        min_args: 0,
+        max_args: 1,
+        handler: help_command,
+    });
+    commands.register(Command {
+        name: "nick",
+        usage: "<name>",
+        help: "changes your username",
+        min_args: 1,
+        max_args: 1,
+        handler: nick_command,
+    });
+    commands.register(Command {
+        name: "me",
+        usage: "<action>",
+        help: "describes what you're doing, e.g. /me waves",
+        min_args: 1,
+        max_args: 1,
+        handler: me_command,
+    });
+    commands.register(Command {
+        name: "join",
+        usage: "<room>",
+        help: "joins a room, creating it if need be",
+        min_args: 1,
+        max_args: 1,
+        handler: join_command,
+    });
+    commands.register(Command {
+        name: "msg",
+        usage: "<name> <message>",
+        help: "sends someone a direct message",
+        min_args: 2,
+        max_args: 2,
+        handler: msg_command,
+    });
+    commands.register(Command {
+        name: "topic",
+        usage: "[topic]",
+        help: "shows this room's topic, or changes it",
+        min_args: 0,
+        max_args: 1,
+        handler: topic_command,
+    });
+    commands.register(Command {
+        name: "unban",
+        usage: "<name>",
+        help: "lifts a ban (moderators only)",
+        min_args: 1,
+        max_args: 1,
+        handler: unban_command,
+    });
+    commands.register(Command {
+        name: "who",
+        usage: "[room]",
+        help: "lists who is in this room, or another you're in",
+        min_args: 0,
+        max_args: 1,
+        handler: who_command,
+    });
+    commands
+}
+
+fn ban_command(server: &mut ChatServer, inv: &Invocation)
+               -> Result<(), (&'static str, String)> {
+    if !server.is_moderator(inv.client_id) {
+        return Err((ERR_FORBIDDEN, String::from("only moderators may ban")));
+    }
+    let name = match username::normalize(&inv.args[0][..]) {
+        Ok(name) => name,
+        Err(e) => return Err((ERR_INVALID_USERNAME, e.to_string())),
+    };
+    let reason = inv.args.get(1).cloned()
+        .unwrap_or(String::from("no reason given"));
+
+    server.ban(name.clone(), reason);
+    server.notify(inv.client_id, format!("banned {}", name),
+                  inv.request_id.clone());
+    Ok(())
+}
+
+fn help_command(server: &mut ChatServer, inv: &Invocation)
+                -> Result<(), (&'static str, String)> {
+    let text = match inv.args.first() {
+        Some(name) => {
+            let name = name.trim_left_matches('/');
+            match server.commands.get(name) {
+                Some(command) => {
+                    format!("/{} {}: {}", command.name, command.usage,
+                            command.help)
+                },
+                None => {
+                    return Err((ERR_UNKNOWN_COMMAND,
+                                format!("unknown command /{}", name)));
+                },
+            }
+        },
+        None => {
+            let lines: Vec<String> = server.commands.all().iter()
+                .map(|command| format!("/{} {}: {}", command.name,
+                                       command.usage, command.help))
+                .collect();
+            lines.join("\n")
+        },
+    };
+    server.notify(inv.client_id, text, inv.request_id.clone());
+    Ok(())
+}
+
+fn nick_command(server: &mut ChatServer, inv: &Invocation)
+                -> Result<(), (&'static str, String)> {
+    match server.check_username(&inv.args[0][..], inv.client_id,
+                                &inv.request_id) {
+        Ok(name) => server.set_username(inv.client_id, name),
+        Err(msg) => server.send_to(&vec![inv.client_id], msg),
+    }
+    Ok(())
+}
+
+fn me_command(server: &mut ChatServer, inv: &Invocation)
+              -> Result<(), (&'static str, String)> {
+    let text = format!("* {} {}", server.display_name(inv.client_id),
+                       inv.args[0]);
+    server.send_text(&inv.room[..], inv.client_id, text, None,
+                     inv.request_id.clone());
+    Ok(())
+}
+
+fn join_command(server: &mut ChatServer, inv: &Invocation)
+                -> Result<(), (&'static str, String)> {
+    match roomname::normalize(&inv.args[0][..]) {
+        Ok(room) => {
+            server.join_room(&room[..], inv.client_id);
+            Ok(())
+        },
+        Err(e) => Err((ERR_BAD_REQUEST, e.to_string())),
+    }
+}
+
+fn msg_command(server: &mut ChatServer, inv: &Invocation)
+               -> Result<(), (&'static str, String)> {
+    server.direct_message(inv.client_id, &inv.args[0][..],
+                          inv.args[1].clone(), inv.request_id.clone());
+    Ok(())
+}
+
+fn unban_command(server: &mut ChatServer, inv: &Invocation)
+                 -> Result<(), (&'static str, String)> {
+    if !server.is_moderator(inv.client_id) {
+        return Err((ERR_FORBIDDEN, String::from("only moderators may unban")));
+    }
+    let ban = match server.find_ban(&inv.args[0][..]) {
+        Some(ban) => ban,
+        None => {
+            return Err((ERR_BAD_REQUEST,
+                        format!("{} isn't banned", inv.args[0])));
+        },
+    };
+
+    if let Err(e) = server.storage.remove_ban(&ban.name[..]) {
+        println!("failed to store unban: {}", e);
+    }
+    server.notify(inv.client_id, format!("unbanned {}", ban.name),
+                  inv.request_id.clone());
+    Ok(())
+}
+
+fn who_command(server: &mut ChatServer, inv: &Invocation)
+               -> Result<(), (&'static str, String)> {
+    let room_name = match inv.args.first() {
+        Some(room) => match roomname::normalize(&room[..]) {
+            Ok(room) => room,
+            Err(e) => return Err((ERR_BAD_REQUEST, e.to_string())),
+        },
+        None => inv.room.clone(),
+    };
+    if !server.is_room_member(&room_name[..], inv.client_id) {
+        return Err((ERR_NOT_IN_ROOM, format!("you aren't in {}", room_name)));
+    }
+
+    let mut members: Vec<String> = server.rooms[&room_name].members.iter()
+        .filter_map(|id| server.clients.get(id))
+        .map(|client| {
+            let name = server.display_name(client.client_id);
+            match client.presence {
+                Presence::Online => name,
+                Presence::Away => format!("{} (away)", name),
+                Presence::DoNotDisturb => {
+                    format!("{} (do not disturb)", name)
+                },
+            }
+        })
+        .collect();
+    members.sort();
+    let text = format!("in {}: {}", room_name, members.join(", "));
+    server.notify(inv.client_id, text, inv.request_id.clone());
+    Ok(())
+}
+
+fn topic_command(server: &mut ChatServer, inv: &Invocation)
+                 -> Result<(), (&'static str, String)> {
+    if let Some(topic) = inv.args.first() {
+        server.change_room_info(&inv.room[..], inv.client_id,
+                                Some(topic.clone()), None,
+                                inv.request_id.clone());
+        return Ok(());
+    }
+
+    if !server.is_room_member(&inv.room[..], inv.client_id) {
+        return Err((ERR_NOT_IN_ROOM, format!("you aren't in {}", inv.room)));
     }
+    let topic = server.rooms.get(&inv.room)
+        .and_then(|room| room.record.topic.clone());
+    let text = match topic {
+        Some(topic) => format!("topic of {}: {}", inv.room, topic),
+        None => format!("{} has no topic", inv.room),
+    };
+    server.notify(inv.client_id, text, inv.request_id.clone());
+    Ok(())
 }
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use chat::ChatServer;
+
+/// A slash command as typed by a client, e.g. `/msg alice hi`.
+pub struct Invocation {
+    pub client_id: i64,
+    /// room the command was typed in
+    pub room: String,
+    pub args: Vec<String>,
+    pub request_id: Option<String>,
+}
+
+/// Runs a command. On failure, returns the error code and text to send
+/// back.
+pub type Handler = fn(&mut ChatServer, &Invocation)
+                      -> Result<(), (&'static str, String)>;
+
+pub struct Command {
+    pub name: &'static str,
+    /// arguments as shown in help, e.g. `<name> <message>`
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub min_args: usize,
+    /// arguments taken; the last one takes the rest of the line
+    pub max_args: usize,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// Splits the text after a command's name into its arguments, or
+    /// returns the usage to show if there are too few.
+    pub fn parse_args(&self, text: &str) -> Result<Vec<String>, String> {
+        let mut args = Vec::new();
+        let mut rest = text.trim();
+        while rest.len() > 0 && args.len() < self.max_args {
+            if args.len() + 1 == self.max_args {
+                args.push(String::from(rest));
+                break;
+            }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            args.push(String::from(&rest[..end]));
+            rest = rest[end..].trim_left();
+        }
+
+        if args.len() < self.min_args {
+            return Err(format!("usage: /{} {}", self.name, self.usage));
+        }
+        Ok(args)
+    }
+}
+
+/// The slash commands a server knows, by name.
+///
+/// Teams can add their own by registering them before the server starts:
+///
+///     let mut commands = chat::builtin_commands();
+///     commands.register(Command { name: "deploy", ... });
+///     ChatServer::start(config, commands);
+pub struct Commands {
+    commands: BTreeMap<String, Command>,
+}
+
+impl Commands {
+    pub fn new() -> Commands {
+        Commands { commands: BTreeMap::new() }
+    }
+
+    /// Adds a command, replacing any other of the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.insert(command.name.to_lowercase(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(&name.to_lowercase())
+    }
+
+    /// Every command, ordered by name.
+    pub fn all(&self) -> Vec<&Command> {
+        self.commands.values().collect()
+    }
+}
+
+/// Splits a message starting with `/` into the command's name and the rest
+/// of the line. Messages starting with `//` aren't commands; see
+/// `unescape()`.
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with("/") || text.starts_with("//") {
+        return None;
+    }
+    let line = &text[1..];
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    Some((&line[..end], &line[end..]))
+}
+
+/// Turns a leading `//` into `/`, so clients can send text starting with a
+/// slash.
+pub fn unescape(text: String) -> String {
+    if text.starts_with("//") {
+        String::from(&text[1..])
+    } else {
+        text
+    }
+}
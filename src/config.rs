@@ -0,0 +1,99 @@
+use std::env;
+use std::str::FromStr;
+
+use protocol::PROTOCOL_VERSION;
+use queue::OverflowPolicy;
+
+/// Server settings, read from `SHITCHAT_*` environment variables.
+pub struct Config {
+    pub bind_addr: String,
+    pub port: u16,
+    /// max messages buffered per client before the overflow policy applies
+    pub outbound_queue_len: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// seconds a socket write may block before the client is dropped
+    pub write_timeout_secs: u64,
+    /// PEM certificate chain and private key; TLS is enabled when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// messages kept in memory per room
+    pub history_len: usize,
+    /// messages replayed to a client when it joins a room
+    pub history_replay_len: usize,
+    /// append-only log persisting messages, accounts, rooms and bans across
+    /// restarts; state is kept in memory only if unset
+    pub storage_path: Option<String>,
+    /// shortest password accepted for new accounts
+    pub min_password_len: usize,
+    /// HMAC secret for session tokens; when set, /ws/ and /stats/ require a
+    /// valid token
+    pub auth_secret: Option<String>,
+    /// seconds a disconnected client's session is held for it to resume
+    pub resume_grace_secs: u64,
+    /// messages per second each client may send on average; 0 for no limit
+    pub rate_limit: f64,
+    /// messages a client may send in a burst before the rate limit applies
+    pub rate_burst: usize,
+    /// seconds after which a client that stops refreshing its typing notice
+    /// is taken to have stopped typing
+    pub typing_timeout_secs: u64,
+    /// seconds without activity after which an online client is shown as away
+    pub idle_timeout_secs: u64,
+    /// accounts which may edit and delete anyone's messages
+    pub moderators: Vec<String>,
+    /// wire protocol version for connections which don't ask for one
+    pub default_protocol_version: u32,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => panic!("{} has an invalid value: {}", name, value),
+        },
+        Err(_) => default,
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let overflow_policy = match env::var("SHITCHAT_OVERFLOW_POLICY") {
+            Ok(value) => match OverflowPolicy::from_str(&value[..]) {
+                Some(policy) => policy,
+                None => panic!("SHITCHAT_OVERFLOW_POLICY has an invalid \
+                                value: {}", value),
+            },
+            Err(_) => OverflowPolicy::DropOldest,
+        };
+
+        Config {
+            bind_addr: env_or("SHITCHAT_BIND_ADDR", String::from("127.0.0.1")),
+            port: env_or("SHITCHAT_PORT", 8080),
+            outbound_queue_len: env_or("SHITCHAT_QUEUE_LEN", 256),
+            overflow_policy: overflow_policy,
+            write_timeout_secs: env_or("SHITCHAT_WRITE_TIMEOUT", 10),
+            tls_cert_path: env::var("SHITCHAT_TLS_CERT").ok(),
+            tls_key_path: env::var("SHITCHAT_TLS_KEY").ok(),
+            history_len: env_or("SHITCHAT_HISTORY_LEN", 1000),
+            history_replay_len: env_or("SHITCHAT_HISTORY_REPLAY_LEN", 50),
+            storage_path: env::var("SHITCHAT_STORAGE_PATH").ok(),
+            min_password_len: env_or("SHITCHAT_MIN_PASSWORD_LEN", 8),
+            auth_secret: env::var("SHITCHAT_AUTH_SECRET").ok(),
+            resume_grace_secs: env_or("SHITCHAT_RESUME_GRACE", 30),
+            rate_limit: env_or("SHITCHAT_RATE_LIMIT", 5.0),
+            rate_burst: env_or("SHITCHAT_RATE_BURST", 20),
+            typing_timeout_secs: env_or("SHITCHAT_TYPING_TIMEOUT", 5),
+            idle_timeout_secs: env_or("SHITCHAT_IDLE_TIMEOUT", 300),
+            moderators: env::var("SHITCHAT_MODERATORS")
+                .map(|names| {
+                    names.split(',')
+                        .map(|name| String::from(name.trim()))
+                        .filter(|name| name.len() > 0)
+                        .collect()
+                })
+                .unwrap_or(Vec::new()),
+            default_protocol_version: env_or("SHITCHAT_PROTOCOL",
+                                             PROTOCOL_VERSION),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+
+use chat::ServerMessage;
+use storage::Storage;
+
+/// The most recent messages for every room, kept in memory in front of the
+/// server's `Storage`.
+pub struct History {
+    rooms: HashMap<String, VecDeque<ServerMessage>>,
+    capacity: usize,
+}
+
+impl History {
+    /// Creates a history keeping `capacity` messages per room, primed with
+    /// the newest messages of the given rooms from storage.
+    pub fn load(capacity: usize, storage: &Storage, rooms: &Vec<String>)
+                -> History {
+        let mut history = History {
+            rooms: HashMap::new(),
+            capacity: capacity,
+        };
+        for room in rooms.iter() {
+            for msg in storage.messages(&room[..], None, capacity) {
+                history.record(&room[..], msg);
+            }
+        }
+        history
+    }
+
+    /// Keeps a message sent to a room, evicting the room's oldest message if
+    /// it is full.
+    pub fn record(&mut self, room: &str, msg: ServerMessage) {
+        let msgs = self.rooms.entry(String::from(room))
+            .or_insert_with(VecDeque::new);
+        if msgs.len() >= self.capacity {
+            msgs.pop_front();
+        }
+        msgs.push_back(msg);
+    }
+
+    /// Replaces the text of a message, wherever it is held.
+    pub fn edit(&mut self, id: u64, text: String) {
+        for msgs in self.rooms.values_mut() {
+            for msg in msgs.iter_mut().filter(|m| m.id() == Some(id)) {
+                msg.set_text(text.clone());
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        for msgs in self.rooms.values_mut() {
+            msgs.retain(|m| m.id() != Some(id));
+        }
+    }
+
+    /// Returns up to `limit` messages in a room older than `before` (or the
+    /// newest messages if `before` is None), oldest first, reading through
+    /// to storage for anything no longer held in memory.
+    pub fn fetch(&self, storage: &Storage, room: &str, before: Option<u64>,
+                 limit: usize) -> Vec<ServerMessage> {
+        let mut page: Vec<ServerMessage> = match self.rooms.get(room) {
+            Some(msgs) => msgs.iter()
+                .rev()
+                .filter(|m| is_before(m, before))
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        page.reverse();
+
+        if page.len() < limit {
+            let oldest = page.first().and_then(|m| m.id()).or(before);
+            let mut older = storage.messages(room, oldest, limit - page.len());
+            older.extend(page.into_iter());
+            page = older;
+        }
+        page
+    }
+}
+
+/// Whether a message's id is lower than `before`, if given.
+pub fn is_before(msg: &ServerMessage, before: Option<u64>) -> bool {
+    match (msg.id(), before) {
+        (_, None) => true,
+        (Some(id), Some(before)) => id < before,
+        (None, Some(_)) => false,
+    }
+}
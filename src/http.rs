@@ -1,8 +1,8 @@
-use std::net::{TcpStream};
 use bufstream::BufStream;
-use std::sync::{Arc, Mutex};
-
-use chat::ChatServer;
+use std::sync::Arc;
+use chat::ChatServerHandle;
+use config::Config;
+use stream::Stream;
 
 // Client request:
 // ===============
@@ -45,7 +45,6 @@ pub struct Header {
 
 impl Header {
     fn from_str(string: &str) -> Header {
-        println!("header: {}", string);
         let mut kv = string.splitn(2, ':');
         let key = String::from(kv.next().unwrap());
         let value = String::from(kv.next().unwrap().trim_left());
@@ -56,29 +55,61 @@ impl Header {
     }
 }
 
+/// Decodes `%XX` escapes and `+` in a query string component.
+fn url_decode(string: &str) -> String {
+    let bytes = string.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]);
+                match u8::from_str_radix(&hex[..], 16) {
+                    Ok(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    },
+                    Err(_) => decoded.push(b'%'),
+                }
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded[..]).into_owned()
+}
+
 pub struct Request {
     pub method: HTTPMethod,
     pub path: String,
+    /// the part of the request target after `?`, if any
+    pub query: String,
     pub protocol: String,
     pub headers: Vec<Header>,
-    pub stream: BufStream<TcpStream>,
-    pub chat_server: Arc<Mutex<ChatServer>>,
+    pub stream: BufStream<Stream>,
+    pub chat_server: ChatServerHandle,
+    pub config: Arc<Config>,
 }
 
 impl Request {
     pub fn new(request_lines: &Vec<String>,
-               stream: BufStream<TcpStream>,
-               chat_server: Arc<Mutex<ChatServer>>) -> Request {
+               stream: BufStream<Stream>,
+               chat_server: ChatServerHandle,
+               config: Arc<Config>) -> Request {
         let first_line = &request_lines[0];
 
         let frags: &Vec<&str> = &first_line[..]
             .trim()
             .split_whitespace()
             .collect::<Vec<&str>>();
-        let (method, path, protocol) = match frags.len() {
+        let (method, path, query, protocol) = match frags.len() {
             3 => {
                 let method = HTTPMethod::from_str(frags[0]);
-                (method, String::from(frags[1]), String::from(frags[2]))
+                let mut target = frags[1].splitn(2, '?');
+                let path = String::from(target.next().unwrap());
+                let query = String::from(target.next().unwrap_or(""));
+                (method, path, query, String::from(frags[2]))
             }
             _ => {
                 panic!("Malformed request: {}", first_line);
@@ -94,10 +125,12 @@ impl Request {
         Request{
             method: method,
             path: path,
+            query: query,
             protocol: protocol,
             headers: headers,
             stream: stream,
             chat_server: chat_server,
+            config: config,
         }
     }
 
@@ -112,6 +145,39 @@ impl Request {
         }
     }
 
+    pub fn get_query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if url_decode(k) == name => {
+                        Some(url_decode(v))
+                    },
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    pub fn get_cookie(&self, name: &str) -> Option<String> {
+        let cookies = match self.get_header("Cookie") {
+            Some(cookies) => cookies,
+            None => return None,
+        };
+        let cookie = cookies
+            .split(';')
+            .filter_map(|pair| {
+                let mut kv = pair.trim().splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k == name => Some(String::from(v)),
+                    _ => None,
+                }
+            })
+            .next();
+        cookie
+    }
+
     pub fn is_websocket(&self) -> bool {
         let connection = self.get_header("Connection");
         let upgrade = self.get_header("Upgrade");
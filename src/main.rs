@@ -1,20 +1,44 @@
 //#![feature(old_io)]
 //#![feature(collections)]
 
+mod auth;
 mod http;
 mod views;
 mod routes;
 mod ws;
 mod server;
 mod chat;
+mod commands;
+mod config;
+mod history;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod reactions;
+mod roomname;
+mod storage;
+mod stream;
+mod tls;
+mod typing;
+mod username;
 
 extern crate sha1;
 extern crate rustc_serialize;
 extern crate bufstream;
 extern crate rand;
 extern crate byteorder;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate bcrypt;
+extern crate sha2;
+extern crate unicode_normalization;
+extern crate unicode_security;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+extern crate rmp_serde;
 
 
 fn main() {
-    server::server("127.0.0.1", 8080);
+    server::server(config::Config::from_env());
 }
@@ -0,0 +1,182 @@
+use std::str::from_utf8;
+
+use rmp_serde;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+use serde_json::{self, Value};
+
+use chat::{ClientMessage, ServerMessage, ERR_BAD_REQUEST, ERR_UNKNOWN_COMMAND};
+
+/// Current protocol version, documented in PROTOCOL.md: JSON objects named by
+/// a `type` field, with every other field named too.
+pub static PROTOCOL_VERSION: u32 = 2;
+
+/// The legacy `rustc_serialize` enum encoding, `{"variant": ...,
+/// "fields": [...]}`, kept while clients migrate.
+pub static LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+pub fn is_supported(version: u32) -> bool {
+    version == PROTOCOL_VERSION || version == LEGACY_PROTOCOL_VERSION
+}
+
+/// How messages are encoded on a connection, chosen by WebSocket subprotocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    /// JSON in text frames
+    Json,
+    /// MessagePack in binary frames; only for protocol version 2 and later
+    MessagePack,
+}
+
+impl Codec {
+    pub fn from_subprotocol(name: &str) -> Option<Codec> {
+        match name {
+            // "chat" predates the codecs
+            "shitchat.json" | "chat" => Some(Codec::Json),
+            "shitchat.msgpack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn subprotocol(&self) -> &'static str {
+        match *self {
+            Codec::Json => "shitchat.json",
+            Codec::MessagePack => "shitchat.msgpack",
+        }
+    }
+
+    /// Whether messages go in binary rather than text frames.
+    pub fn is_binary(&self) -> bool {
+        *self == Codec::MessagePack
+    }
+
+    pub fn supports(&self, version: u32) -> bool {
+        is_supported(version)
+            && (*self == Codec::Json || version != LEGACY_PROTOCOL_VERSION)
+    }
+
+    pub fn encode(&self, msg: &ServerMessage, version: u32) -> Vec<u8> {
+        match *self {
+            Codec::Json if version == LEGACY_PROTOCOL_VERSION => {
+                json::encode(msg).unwrap().into_bytes()
+            },
+            Codec::Json => serde_json::to_vec(msg).unwrap(),
+            // named, so messages keep the same shape as in JSON
+            Codec::MessagePack => rmp_serde::to_vec_named(msg).unwrap(),
+        }
+    }
+
+    /// Decodes a client message, along with the `request_id` a client may
+    /// send beside its fields to match replies to its requests.
+    ///
+    /// On failure, returns the error to send back.
+    pub fn decode(&self, data: &[u8], version: u32)
+                  -> Result<(ClientMessage, Option<String>), ServerMessage> {
+        let parsed = match *self {
+            Codec::Json => {
+                let text = match from_utf8(data) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        return Err(ServerMessage::error(
+                            ERR_BAD_REQUEST, e.to_string(), None));
+                    },
+                };
+                if version == LEGACY_PROTOCOL_VERSION {
+                    return decode_legacy(text);
+                }
+                serde_json::from_str(text).map_err(|e| e.to_string())
+            },
+            Codec::MessagePack => {
+                rmp_serde::from_slice(data).map_err(|e| e.to_string())
+            },
+        };
+        let data: Value = match parsed {
+            Ok(data) => data,
+            Err(e) => {
+                return Err(ServerMessage::error(ERR_BAD_REQUEST, e, None));
+            },
+        };
+        decode_value(data)
+    }
+}
+
+fn decode_value(data: Value)
+                -> Result<(ClientMessage, Option<String>), ServerMessage> {
+    let request_id = match data.get("request_id") {
+        Some(&Value::String(ref id)) => Some(id.clone()),
+        Some(&Value::Number(ref id)) => Some(id.to_string()),
+        _ => None,
+    };
+
+    match serde_json::from_value(data) {
+        Ok(msg) => Ok((msg, request_id)),
+        Err(e) => {
+            Err(ServerMessage::error(ERR_BAD_REQUEST, e.to_string(),
+                                     request_id))
+        },
+    }
+}
+
+/// Client messages which have gained fields since legacy clients were
+/// written, with their number of fields now. The added fields are optional,
+/// and come last so legacy clients can leave them out.
+static LEGACY_GROWN_VARIANTS: &'static [(&'static str, usize)] = &[
+    ("TextMessage", 2),
+    ("RoomTextMessage", 3),
+];
+
+/// Fills in the optional fields a legacy client left out with nulls, which
+/// decode as `None`; the legacy encoding lists fields by position, so they
+/// can't otherwise be missing.
+fn pad_legacy_fields(data: &mut Json) {
+    let len = match data.find("variant").and_then(|v| v.as_string()) {
+        Some(variant) => {
+            match LEGACY_GROWN_VARIANTS.iter().find(|&&(v, _)| v == variant) {
+                Some(&(_, len)) => len,
+                None => return,
+            }
+        },
+        None => return,
+    };
+    if let Json::Object(ref mut object) = *data {
+        if let Some(&mut Json::Array(ref mut fields)) =
+            object.get_mut("fields") {
+            while fields.len() < len {
+                fields.push(Json::Null);
+            }
+        }
+    }
+}
+
+fn decode_legacy(text: &str)
+                 -> Result<(ClientMessage, Option<String>), ServerMessage> {
+    let mut data = match Json::from_str(text) {
+        Ok(data) => data,
+        Err(e) => {
+            return Err(ServerMessage::error(ERR_BAD_REQUEST, e.to_string(),
+                                            None));
+        },
+    };
+    let request_id = match data.find("request_id") {
+        Some(&Json::String(ref id)) => Some(id.clone()),
+        Some(&Json::I64(id)) => Some(id.to_string()),
+        Some(&Json::U64(id)) => Some(id.to_string()),
+        _ => None,
+    };
+
+    pad_legacy_fields(&mut data);
+    let mut decoder = json::Decoder::new(data);
+    match ClientMessage::decode(&mut decoder) {
+        Ok(msg) => Ok((msg, request_id)),
+        Err(json::DecoderError::UnknownVariantError(variant)) => {
+            Err(ServerMessage::error(
+                ERR_UNKNOWN_COMMAND,
+                format!("unknown command: {}", variant),
+                request_id))
+        },
+        Err(e) => {
+            Err(ServerMessage::error(ERR_BAD_REQUEST, e.to_string(),
+                                     request_id))
+        },
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+
+use chat::ServerMessage;
+
+/// What to do when a client's outbound queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// discard the oldest queued message to make room
+    DropOldest,
+    /// disconnect the client with close code 1008 (policy violation)
+    DropClient,
+    /// replace a queued message superseded by the new one, falling back to
+    /// dropping the oldest
+    Coalesce,
+}
+
+impl OverflowPolicy {
+    pub fn from_str(string: &str) -> Option<OverflowPolicy> {
+        match string {
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "drop-client" => Some(OverflowPolicy::DropClient),
+            "coalesce" => Some(OverflowPolicy::Coalesce),
+            _ => None,
+        }
+    }
+}
+
+/// WebSocket close code sent in answer to a client's own close frame.
+pub static CLOSE_NORMAL: u16 = 1000;
+
+/// WebSocket close code sent to clients dropped for falling behind.
+pub static CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// WebSocket close code sent to a connection replaced by a newer one for the
+/// same identity.
+pub static CLOSE_REPLACED: u16 = 4000;
+
+/// Items handed to a client's writer thread.
+pub enum Outbound {
+    Message(ServerMessage),
+    Close(u16),
+    /// answer to a ping, with its payload
+    Pong(Vec<u8>),
+    /// the connection is gone; stop without consuming anything
+    Detached,
+}
+
+struct QueueState {
+    msgs: VecDeque<ServerMessage>,
+    closing: Option<u16>,
+    /// payload of the latest unanswered ping
+    pong: Option<Vec<u8>>,
+    detached: bool,
+    dropped: u64,
+}
+
+/// Bounded, multi-producer queue of messages waiting to be written to a
+/// single client.
+#[derive(Clone)]
+pub struct OutboundQueue {
+    inner: Arc<(Mutex<QueueState>, Condvar)>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> OutboundQueue {
+        let state = QueueState {
+            msgs: VecDeque::with_capacity(capacity),
+            closing: None,
+            pong: None,
+            detached: false,
+            dropped: 0,
+        };
+        OutboundQueue {
+            inner: Arc::new((Mutex::new(state), Condvar::new())),
+            capacity: capacity,
+            policy: policy,
+        }
+    }
+
+    /// Enqueues `msg`, applying the overflow policy if the queue is full.
+    ///
+    /// Returns false if the client must be disconnected.
+    pub fn push(&self, msg: ServerMessage) -> bool {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+
+        if state.closing.is_some() {
+            return false;
+        }
+
+        if state.msgs.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.msgs.pop_front();
+                },
+                OverflowPolicy::DropClient => {
+                    state.msgs.clear();
+                    state.closing = Some(CLOSE_POLICY_VIOLATION);
+                    state.dropped += 1;
+                    cvar.notify_one();
+                    return false;
+                },
+                OverflowPolicy::Coalesce => {
+                    let pos = match msg.coalesce_key() {
+                        Some(key) => state.msgs.iter()
+                            .position(|m| m.coalesce_key() == Some(key)),
+                        None => None,
+                    };
+                    match pos {
+                        Some(i) => {
+                            state.msgs[i] = msg;
+                            state.dropped += 1;
+                            return true;
+                        },
+                        None => {
+                            state.msgs.pop_front();
+                        },
+                    }
+                },
+            }
+            state.dropped += 1;
+        }
+
+        state.msgs.push_back(msg);
+        cvar.notify_one();
+        true
+    }
+
+    /// Blocks until there is something for the writer thread to do.
+    pub fn pop(&self) -> Outbound {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if let Some(code) = state.closing {
+                return Outbound::Close(code);
+            }
+            if state.detached {
+                return Outbound::Detached;
+            }
+            if let Some(data) = state.pong.take() {
+                return Outbound::Pong(data);
+            }
+            if let Some(msg) = state.msgs.pop_front() {
+                return Outbound::Message(msg);
+            }
+            state = cvar.wait(state).unwrap();
+        }
+    }
+
+    /// Tells the writer thread to hang up with the given close code,
+    /// discarding anything still queued.
+    pub fn close(&self, code: u16) {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().unwrap();
+        state.msgs.clear();
+        state.closing = Some(code);
+        cvar.notify_one();
+    }
+
+    /// Has the writer thread answer a ping ahead of any queued messages.
+    pub fn pong(&self, data: Vec<u8>) {
+        let &(ref lock, ref cvar) = &*self.inner;
+        lock.lock().unwrap().pong = Some(data);
+        cvar.notify_one();
+    }
+
+    /// Stops the writer thread while leaving the queue accepting messages,
+    /// so they can be picked up by a later connection with `drain()`.
+    pub fn detach(&self) {
+        let &(ref lock, ref cvar) = &*self.inner;
+        lock.lock().unwrap().detached = true;
+        cvar.notify_one();
+    }
+
+    /// Removes and returns everything queued.
+    pub fn drain(&self) -> Vec<ServerMessage> {
+        let &(ref lock, _) = &*self.inner;
+        lock.lock().unwrap().msgs.drain(..).collect()
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn depth(&self) -> usize {
+        let &(ref lock, _) = &*self.inner;
+        lock.lock().unwrap().msgs.len()
+    }
+
+    /// Number of messages discarded because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        let &(ref lock, _) = &*self.inner;
+        lock.lock().unwrap().dropped
+    }
+}
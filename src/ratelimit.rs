@@ -0,0 +1,42 @@
+/// Token bucket limiting how often a client may send messages.
+///
+/// The bucket holds up to `burst` tokens and refills at `rate` tokens per
+/// second; every message takes one. A rate of zero disables the limit.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    /// milliseconds since the epoch when the bucket was last refilled
+    refilled_at: u64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: usize, now: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Takes a token if one is available, returning whether the message may
+    /// go through.
+    pub fn allow(&mut self, now: u64) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        let elapsed = now.saturating_sub(self.refilled_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
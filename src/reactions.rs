@@ -0,0 +1,82 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Longest emoji accepted as a reaction, in characters; long enough for
+/// shortcodes like `:thumbsup:` and multi-codepoint emoji.
+pub static MAX_EMOJI_LEN: usize = 32;
+
+/// Everyone who reacted to a message with one emoji.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub client_ids: Vec<i64>,
+}
+
+/// The reactions to one message, as listed in a `History`.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize)]
+pub struct MessageReactions {
+    pub message_id: u64,
+    pub reactions: Vec<Reaction>,
+}
+
+/// Reactions to room messages: who reacted to each message with what.
+pub struct Reactions {
+    /// reacting client_ids by emoji, by message id
+    messages: HashMap<u64, BTreeMap<String, BTreeSet<i64>>>,
+}
+
+impl Reactions {
+    pub fn new() -> Reactions {
+        Reactions { messages: HashMap::new() }
+    }
+
+    /// Adds a client's reaction. Returns whether it is new, and so whether
+    /// the room should be told.
+    pub fn add(&mut self, message_id: u64, emoji: &str, client_id: i64)
+               -> bool {
+        self.messages.entry(message_id)
+            .or_insert_with(BTreeMap::new)
+            .entry(String::from(emoji))
+            .or_insert_with(BTreeSet::new)
+            .insert(client_id)
+    }
+
+    /// Takes back a client's reaction. Returns whether it had reacted.
+    pub fn remove(&mut self, message_id: u64, emoji: &str, client_id: i64)
+                  -> bool {
+        let removed = match self.messages.get_mut(&message_id) {
+            Some(emojis) => {
+                let removed = match emojis.get_mut(emoji) {
+                    Some(client_ids) => client_ids.remove(&client_id),
+                    None => false,
+                };
+                emojis.retain(|_, client_ids| client_ids.len() > 0);
+                removed
+            },
+            None => false,
+        };
+        if self.messages.get(&message_id).map_or(false, |e| e.is_empty()) {
+            self.messages.remove(&message_id);
+        }
+        removed
+    }
+
+    /// A message's reactions, ordered by emoji.
+    pub fn of(&self, message_id: u64) -> Vec<Reaction> {
+        match self.messages.get(&message_id) {
+            Some(emojis) => emojis.iter()
+                .map(|(emoji, client_ids)| Reaction {
+                    emoji: emoji.clone(),
+                    count: client_ids.len(),
+                    client_ids: client_ids.iter().cloned().collect(),
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drops the reactions to a deleted message.
+    pub fn forget_message(&mut self, message_id: u64) {
+        self.messages.remove(&message_id);
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest room name accepted, in characters after normalization.
+pub static MAX_LEN: usize = 64;
+
+/// Why a room name was refused.
+#[derive(Debug, PartialEq)]
+pub enum RoomNameError {
+    Empty,
+    TooLong,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for RoomNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RoomNameError::Empty => write!(f, "room names can't be empty"),
+            RoomNameError::TooLong => {
+                write!(f, "room names can be at most {} characters", MAX_LEN)
+            },
+            RoomNameError::InvalidCharacter(c) => {
+                write!(f, "room names may only contain letters, digits, '_', \
+                           '-' and '.', not {:?}", c)
+            },
+        }
+    }
+}
+
+fn is_allowed(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Validates the name of a room a client wants to join, returning it in
+/// Unicode NFKC form.
+pub fn normalize(name: &str) -> Result<String, RoomNameError> {
+    let name: String = name.trim().nfkc().collect();
+
+    if name.is_empty() {
+        return Err(RoomNameError::Empty);
+    }
+    if name.chars().count() > MAX_LEN {
+        return Err(RoomNameError::TooLong);
+    }
+    if let Some(c) = name.chars().filter(|c| !is_allowed(*c)).next() {
+        return Err(RoomNameError::InvalidCharacter(c));
+    }
+    Ok(name)
+}
@@ -6,6 +6,7 @@ pub fn route_request(request: Request) -> (String, u32) {
         match (&request.method, &request.path[..]) {
         (&HTTPMethod::GET, "/") => views::index,
         (&HTTPMethod::GET, "/ws/") => views::ws,
+        (&HTTPMethod::GET, "/stats/") => views::stats,
         _ => views::error_404,
     };
 
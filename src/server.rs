@@ -1,18 +1,31 @@
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
 use std::io::BufRead;
 use std::io::{Write};
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::io::Result;
 
 use bufstream::BufStream;
 
 use http;
 use routes;
-use chat::ChatServer;
+use chat::{self, ChatServer, ChatServerHandle};
+use config::Config;
+use stream::Stream;
+use tls;
+
+fn reason_phrase(status: u32) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    }
+}
 
 fn render_response(content: &str, status: u32) -> String {
-    let status_line = format!("HTTP/1.1 {} OK", status);
+    let status_line = format!("HTTP/1.1 {} {}", status, reason_phrase(status));
     let content_len = format!("Content-Length: {}", content.len());
 
     let mut response: Vec<&str> = Vec::new();
@@ -24,7 +37,39 @@ fn render_response(content: &str, status: u32) -> String {
     response.connect("\r\n")
 }
 
-fn handle_client(stream: TcpStream, chat_server: Arc<Mutex<ChatServer>>) {
+/// Headers whose values are secrets, e.g. session tokens.
+static SECRET_HEADERS: &'static [&'static str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+];
+
+/// Blanks out the secrets in a request line or header before it is logged:
+/// credential headers, and query strings, which may hold `token` and
+/// `resume`, wherever they appear.
+fn redact(line: &str) -> String {
+    if let Some(colon) = line.find(':') {
+        let name = line[..colon].trim().to_lowercase();
+        if SECRET_HEADERS.iter().any(|h| *h == &name[..]) {
+            return format!("{}: <redacted>", &line[..colon]);
+        }
+    }
+
+    let mut redacted = String::new();
+    let mut rest = line;
+    while let Some(query) = rest.find('?') {
+        redacted.push_str(&rest[..query]);
+        redacted.push_str("?<redacted>");
+        rest = &rest[query..];
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+fn handle_client(stream: Stream, chat_server: ChatServerHandle,
+                 config: Arc<Config>) {
     let stream2 = stream.try_clone().unwrap();
     let mut buf = BufStream::new(stream);
     let mut request_lines = Vec::new();
@@ -49,21 +94,35 @@ fn handle_client(stream: TcpStream, chat_server: Arc<Mutex<ChatServer>>) {
 
     let mut request = http::Request::new(&request_lines,
                                          BufStream::new(stream2),
-                                         chat_server);
-    println!("{}\n", request_lines.connect("\n"));
+                                         chat_server,
+                                         config);
+    let logged: Vec<String> = request_lines.iter()
+        .map(|line| redact(&line[..]))
+        .collect();
+    println!("{}\n", logged.connect("\n"));
     let (response, status) = routes::route_request(request);
 
     let _ = buf.write_all(
         render_response(&response[..], status).as_bytes());
 }
 
-pub fn server(bind_addr: &str, port: u16) {
-    let addr_str = format!("{}:{}", bind_addr, port);
+pub fn server(config: Config) {
+    let config = Arc::new(config);
+    let addr_str = format!("{}:{}", config.bind_addr, config.port);
     let addr = &addr_str[..];
     println!("listening on {}", addr);
     let mut listener = TcpListener::bind(addr).unwrap();
 //  let (mut acceptor, _) = try!(listener.accept());
-    let chat_server = Arc::new(Mutex::new(ChatServer::new()));
+    let chat_server = ChatServer::start(config.clone(),
+                                        chat::builtin_commands());
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (&Some(ref cert_path), &Some(ref key_path)) => {
+            println!("TLS enabled with certificate {}", cert_path);
+            Some(tls::server_config(&cert_path[..], &key_path[..]).unwrap())
+        },
+        (&None, &None) => None,
+        _ => panic!("TLS requires both a certificate and a key"),
+    };
 
     for stream in listener.incoming() {
         match stream {
@@ -71,9 +130,22 @@ pub fn server(bind_addr: &str, port: u16) {
                 println!("{}", e);
             }
             Ok(stream) => {
+                let stream = match tls_config {
+                    Some(ref tls_config) => {
+                        match Stream::tls(stream, tls_config.clone()) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                println!("{}", e);
+                                continue;
+                            }
+                        }
+                    },
+                    None => Stream::plain(stream),
+                };
                 let cs = chat_server.clone();
+                let config = config.clone();
                 thread::spawn(move|| {
-                    handle_client(stream, cs)
+                    handle_client(stream, cs, config)
                 });
             }
         }
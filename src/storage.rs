@@ -0,0 +1,808 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write, Result, ErrorKind};
+use std::str::from_utf8;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json;
+
+use chat::ServerMessage;
+use username;
+
+/// Log records beyond which compaction is considered.
+static COMPACT_MIN_RECORDS: usize = 1000;
+
+/// A room message as written to the log.
+///
+/// This is kept apart from `ServerMessage`, whose encoding follows the wire
+/// protocol, so that logs outlive protocol changes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub room: String,
+    pub client_id: i64,
+    pub message: String,
+    pub timestamp: u64,
+    /// the message this one replies to, if any
+    pub parent_id: Option<u64>,
+    pub mentions: Vec<i64>,
+}
+
+impl StoredMessage {
+    /// The stored form of a room message; other events aren't stored.
+    pub fn from_message(msg: &ServerMessage) -> Option<StoredMessage> {
+        match *msg {
+            ServerMessage::TextMessage{ref message, client_id, ref room, id,
+                                       timestamp, ref mentions, parent_id} => {
+                Some(StoredMessage {
+                    id: id,
+                    room: room.clone(),
+                    client_id: client_id,
+                    message: message.clone(),
+                    timestamp: timestamp,
+                    parent_id: parent_id,
+                    mentions: mentions.clone(),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    pub fn to_message(&self) -> ServerMessage {
+        ServerMessage::TextMessage{
+            message: self.message.clone(),
+            client_id: self.client_id,
+            room: self.room.clone(),
+            id: self.id,
+            timestamp: self.timestamp,
+            mentions: self.mentions.clone(),
+            parent_id: self.parent_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub name: String,
+    pub password_hash: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomRecord {
+    pub name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// account which created the room, if its creator was logged in
+    pub created_by: Option<String>,
+    /// milliseconds since the epoch; none for the lobby, which always exists
+    pub created_at: Option<u64>,
+}
+
+/// One revision of a room message's text.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable, Serialize,
+         Deserialize)]
+pub struct MessageEdit {
+    /// the new text
+    pub message: String,
+    /// the text it replaced; the first edit's is the original
+    pub previous: String,
+    /// client_id of whoever made the edit
+    pub editor: i64,
+    pub timestamp: u64,
+}
+
+/// One client's reaction to a room message.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredReaction {
+    pub message_id: u64,
+    pub emoji: String,
+    pub client_id: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    /// banned username
+    pub name: String,
+    pub reason: String,
+}
+
+/// Durable chat state: room messages and their reactions, accounts, rooms
+/// and bans.
+pub trait Storage: Send {
+    fn append_message(&mut self, room: &str, msg: &ServerMessage) -> Result<()>;
+    /// Up to `limit` messages in a room with ids below `before`, oldest
+    /// first.
+    fn messages(&self, room: &str, before: Option<u64>, limit: usize)
+                -> Vec<ServerMessage>;
+    /// Highest message id stored, if any.
+    fn last_message_id(&self) -> Option<u64>;
+    /// The message with the given id, and the room it was sent to.
+    fn message(&self, id: u64) -> Option<(String, ServerMessage)>;
+    /// Replaces a message's text, keeping the edit in its history.
+    fn edit_message(&mut self, id: u64, edit: &MessageEdit) -> Result<()>;
+    /// Edits made to a message, oldest first.
+    fn message_edits(&self, id: u64) -> Vec<MessageEdit>;
+    /// Removes a message, its edit history and its reactions.
+    fn delete_message(&mut self, id: u64) -> Result<()>;
+    /// Replies to a message, oldest first.
+    fn replies(&self, parent_id: u64) -> Vec<ServerMessage>;
+    fn reply_count(&self, parent_id: u64) -> usize;
+
+    fn add_reaction(&mut self, reaction: &StoredReaction) -> Result<()>;
+    fn remove_reaction(&mut self, reaction: &StoredReaction) -> Result<()>;
+    /// Every reaction to a stored message.
+    fn reactions(&self) -> Vec<StoredReaction>;
+
+    /// Notes that event ids below `below` may be handed out, so that they
+    /// aren't handed out again after a restart. Returns once that is safely
+    /// stored.
+    fn reserve_ids(&mut self, below: u64) -> Result<()>;
+    /// Ids below this may already have been handed out.
+    fn reserved_ids(&self) -> u64;
+
+    fn save_account(&mut self, account: &Account) -> Result<()>;
+    fn account(&self, name: &str) -> Option<Account>;
+    fn accounts(&self) -> Vec<Account>;
+
+    fn save_room(&mut self, room: &RoomRecord) -> Result<()>;
+    fn rooms(&self) -> Vec<RoomRecord>;
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()>;
+    fn remove_ban(&mut self, name: &str) -> Result<()>;
+    fn bans(&self) -> Vec<Ban>;
+}
+
+/// Storage which lives only as long as the process; used when no storage
+/// path is configured, and in tests.
+pub struct MemoryStorage {
+    /// by room, oldest first
+    messages: HashMap<String, Vec<StoredMessage>>,
+    /// where each message is in `messages`: its room and position
+    index: HashMap<u64, (String, usize)>,
+    /// edits by message id
+    edits: HashMap<u64, Vec<MessageEdit>>,
+    /// ids of replies by the id of the message they answer
+    threads: HashMap<u64, Vec<u64>>,
+    /// by message id
+    reactions: HashMap<u64, Vec<StoredReaction>>,
+    /// keyed by `username::key()` so lookups ignore case, width and
+    /// lookalike letters
+    accounts: HashMap<String, Account>,
+    rooms: HashMap<String, RoomRecord>,
+    /// keyed by `username::key()`, like accounts
+    bans: HashMap<String, Ban>,
+    reserved_ids: u64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage {
+            messages: HashMap::new(),
+            index: HashMap::new(),
+            edits: HashMap::new(),
+            threads: HashMap::new(),
+            reactions: HashMap::new(),
+            accounts: HashMap::new(),
+            rooms: HashMap::new(),
+            bans: HashMap::new(),
+            reserved_ids: 0,
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::RoomMessage(msg) => {
+                if let Some(parent_id) = msg.parent_id {
+                    self.threads.entry(parent_id).or_insert_with(Vec::new)
+                        .push(msg.id);
+                }
+                let msgs = self.messages.entry(msg.room.clone())
+                    .or_insert_with(Vec::new);
+                self.index.insert(msg.id, (msg.room.clone(), msgs.len()));
+                msgs.push(msg);
+            },
+            Record::Edit(id, edit) => {
+                if let Some(&(ref room, i)) = self.index.get(&id) {
+                    if let Some(msg) = self.messages.get_mut(room)
+                        .and_then(|msgs| msgs.get_mut(i)) {
+                        msg.message = edit.message.clone();
+                    }
+                }
+                self.edits.entry(id).or_insert_with(Vec::new).push(edit);
+            },
+            Record::MessageDeleted(id) => {
+                let mut parent_id = None;
+                if let Some((room, i)) = self.index.remove(&id) {
+                    if let Some(msgs) = self.messages.get_mut(&room) {
+                        parent_id = msgs.remove(i).parent_id;
+                        // later messages in the room move down one
+                        for (j, msg) in msgs.iter().enumerate().skip(i) {
+                            if let Some(entry) = self.index.get_mut(&msg.id) {
+                                entry.1 = j;
+                            }
+                        }
+                    }
+                }
+                self.edits.remove(&id);
+                self.reactions.remove(&id);
+                if let Some(replies) = parent_id
+                    .and_then(|parent_id| self.threads.get_mut(&parent_id)) {
+                    replies.retain(|reply| *reply != id);
+                }
+            },
+            Record::ReactionAdded(reaction) => {
+                let reactions = self.reactions.entry(reaction.message_id)
+                    .or_insert_with(Vec::new);
+                if !reactions.contains(&reaction) {
+                    reactions.push(reaction);
+                }
+            },
+            Record::ReactionRemoved(reaction) => {
+                let message_id = reaction.message_id;
+                let emptied = match self.reactions.get_mut(&message_id) {
+                    Some(reactions) => {
+                        reactions.retain(|r| *r != reaction);
+                        reactions.is_empty()
+                    },
+                    None => false,
+                };
+                if emptied {
+                    self.reactions.remove(&message_id);
+                }
+            },
+            Record::Account(account) => {
+                self.accounts.insert(username::key(&account.name[..]), account);
+            },
+            Record::Room(room) => {
+                self.rooms.insert(room.name.clone(), room);
+            },
+            Record::Ban(ban) => {
+                self.bans.insert(username::key(&ban.name[..]), ban);
+            },
+            Record::BanRemoved(name) => {
+                self.bans.remove(&username::key(&name[..]));
+            },
+            Record::IdsReserved(below) => {
+                self.reserved_ids = cmp::max(self.reserved_ids, below);
+            },
+        }
+    }
+
+    fn has_reaction(&self, reaction: &StoredReaction) -> bool {
+        self.reactions.get(&reaction.message_id)
+            .map_or(false, |reactions| reactions.contains(reaction))
+    }
+
+    /// The stored message with the given id.
+    fn stored(&self, id: u64) -> Option<&StoredMessage> {
+        self.index.get(&id).and_then(|&(ref room, i)| {
+            self.messages.get(room).and_then(|msgs| msgs.get(i))
+        })
+    }
+
+    /// Records which recreate the current state from scratch.
+    fn snapshot(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for account in self.accounts.values() {
+            records.push(Record::Account(account.clone()));
+        }
+        for room in self.rooms.values() {
+            records.push(Record::Room(room.clone()));
+        }
+        for ban in self.bans.values() {
+            records.push(Record::Ban(ban.clone()));
+        }
+        for msgs in self.messages.values() {
+            for msg in msgs.iter() {
+                records.push(Record::RoomMessage(msg.clone()));
+            }
+        }
+        for (id, edits) in self.edits.iter() {
+            for edit in edits.iter() {
+                records.push(Record::Edit(*id, edit.clone()));
+            }
+        }
+        for reactions in self.reactions.values() {
+            for reaction in reactions.iter() {
+                records.push(Record::ReactionAdded(reaction.clone()));
+            }
+        }
+        if self.reserved_ids > 0 {
+            records.push(Record::IdsReserved(self.reserved_ids));
+        }
+        records
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append_message(&mut self, room: &str, msg: &ServerMessage) -> Result<()> {
+        let record = try!(message_record(room, msg));
+        self.apply(record);
+        Ok(())
+    }
+
+    fn messages(&self, room: &str, before: Option<u64>, limit: usize)
+                -> Vec<ServerMessage> {
+        let msgs = match self.messages.get(room) {
+            Some(msgs) => msgs,
+            None => return Vec::new(),
+        };
+        let mut page: Vec<ServerMessage> = msgs.iter()
+            .rev()
+            .filter(|m| before.map_or(true, |before| m.id < before))
+            .take(limit)
+            .map(|m| m.to_message())
+            .collect();
+        page.reverse();
+        page
+    }
+
+    fn last_message_id(&self) -> Option<u64> {
+        self.messages.values()
+            .filter_map(|msgs| msgs.last().map(|m| m.id))
+            .max()
+    }
+
+    fn message(&self, id: u64) -> Option<(String, ServerMessage)> {
+        self.stored(id).map(|m| (m.room.clone(), m.to_message()))
+    }
+
+    fn edit_message(&mut self, id: u64, edit: &MessageEdit) -> Result<()> {
+        self.apply(Record::Edit(id, edit.clone()));
+        Ok(())
+    }
+
+    fn message_edits(&self, id: u64) -> Vec<MessageEdit> {
+        self.edits.get(&id).cloned().unwrap_or(Vec::new())
+    }
+
+    fn delete_message(&mut self, id: u64) -> Result<()> {
+        self.apply(Record::MessageDeleted(id));
+        Ok(())
+    }
+
+    fn replies(&self, parent_id: u64) -> Vec<ServerMessage> {
+        let replies = match self.threads.get(&parent_id) {
+            Some(replies) => replies,
+            None => return Vec::new(),
+        };
+        replies.iter()
+            .filter_map(|id| self.stored(*id))
+            .map(|m| m.to_message())
+            .collect()
+    }
+
+    fn reply_count(&self, parent_id: u64) -> usize {
+        self.threads.get(&parent_id).map(|replies| replies.len()).unwrap_or(0)
+    }
+
+    fn add_reaction(&mut self, reaction: &StoredReaction) -> Result<()> {
+        self.apply(Record::ReactionAdded(reaction.clone()));
+        Ok(())
+    }
+
+    fn remove_reaction(&mut self, reaction: &StoredReaction) -> Result<()> {
+        self.apply(Record::ReactionRemoved(reaction.clone()));
+        Ok(())
+    }
+
+    fn reactions(&self) -> Vec<StoredReaction> {
+        self.reactions.values()
+            .flat_map(|reactions| reactions.iter().cloned())
+            .collect()
+    }
+
+    fn reserve_ids(&mut self, below: u64) -> Result<()> {
+        self.apply(Record::IdsReserved(below));
+        Ok(())
+    }
+
+    fn reserved_ids(&self) -> u64 {
+        self.reserved_ids
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<()> {
+        self.apply(Record::Account(account.clone()));
+        Ok(())
+    }
+
+    fn account(&self, name: &str) -> Option<Account> {
+        self.accounts.get(&username::key(name)).cloned()
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.accounts.values().cloned().collect()
+    }
+
+    fn save_room(&mut self, room: &RoomRecord) -> Result<()> {
+        self.apply(Record::Room(room.clone()));
+        Ok(())
+    }
+
+    fn rooms(&self) -> Vec<RoomRecord> {
+        self.rooms.values().cloned().collect()
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        self.apply(Record::Ban(ban.clone()));
+        Ok(())
+    }
+
+    fn remove_ban(&mut self, name: &str) -> Result<()> {
+        self.apply(Record::BanRemoved(String::from(name)));
+        Ok(())
+    }
+
+    fn bans(&self) -> Vec<Ban> {
+        self.bans.values().cloned().collect()
+    }
+}
+
+/// One change to the stored state, as written to the log.
+///
+/// Records are this storage's own format, not the wire protocol's: new
+/// kinds of change get new variants, and room messages are stored as
+/// `StoredMessage`s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Record {
+    RoomMessage(StoredMessage),
+    /// an edit to the message with the given id
+    Edit(u64, MessageEdit),
+    MessageDeleted(u64),
+    ReactionAdded(StoredReaction),
+    ReactionRemoved(StoredReaction),
+    Account(Account),
+    Room(RoomRecord),
+    Ban(Ban),
+    BanRemoved(String),
+    /// event ids below this may have been handed out
+    IdsReserved(u64),
+}
+
+fn message_record(room: &str, msg: &ServerMessage) -> Result<Record> {
+    match StoredMessage::from_message(msg) {
+        Some(ref stored) if stored.room != room => {
+            Err(io::Error::new(ErrorKind::InvalidInput,
+                               "message stored in the wrong room"))
+        },
+        Some(stored) => Ok(Record::RoomMessage(stored)),
+        None => Err(io::Error::new(ErrorKind::InvalidInput,
+                                   "only room messages are stored")),
+    }
+}
+
+/// Reads one line of the log.
+fn read_record(line: &[u8]) -> Option<Record> {
+    from_utf8(line).ok()
+        .and_then(|line| serde_json::from_str(line.trim()).ok())
+}
+
+/// Storage backed by an append-only log of JSON records, one per line.
+///
+/// The whole state is also kept in memory. When superseded records (e.g.
+/// replaced accounts, lifted bans, deleted messages) make up over half the
+/// log, it is compacted by rewriting it from the in-memory state.
+///
+/// The log is written on a thread of its own, so that a slow disk doesn't
+/// hold up the chat server; reads are served from memory.
+pub struct LogStorage {
+    /// feeds the thread writing the log
+    writes: Sender<LogWrite>,
+    state: MemoryStorage,
+    log_records: usize,
+    live_records: usize,
+    /// lines of the log which couldn't be read; compaction would drop them,
+    /// so the log isn't compacted while there are any
+    unreadable: usize,
+}
+
+impl LogStorage {
+    pub fn open(path: &str) -> Result<LogStorage> {
+        let mut state = MemoryStorage::new();
+        let mut log_records = 0;
+        let mut unreadable = 0;
+
+        let mut log = try!(OpenOptions::new()
+            .read(true).append(true).create(true).open(path));
+        let mut torn = false;
+        // where an unreadable last line without a newline starts
+        let mut torn_at = None;
+        {
+            let mut reader = BufReader::new(&log);
+            let mut line = Vec::new();
+            let mut line_number = 0;
+            let mut offset = 0;
+            loop {
+                line.clear();
+                let len = try!(reader.read_until(b'\n', &mut line));
+                if len == 0 {
+                    break;
+                }
+                line_number += 1;
+                torn = line.last() != Some(&b'\n');
+                match read_record(&line[..]) {
+                    Some(record) => {
+                        state.apply(record);
+                        log_records += 1;
+                    },
+                    None if torn => torn_at = Some(offset),
+                    None => {
+                        println!("{} line {}: unreadable record",
+                                 path, line_number);
+                        unreadable += 1;
+                    },
+                }
+                offset += len as u64;
+            }
+        }
+        // a write cut short, e.g. by a crash: drop it if it can't be read,
+        // or else end its line so later records start on one of their own
+        if let Some(len) = torn_at {
+            println!("{}: dropping a record cut short at the end", path);
+            try!(log.set_len(len));
+        } else if torn {
+            try!(log.write_all(b"\n"));
+        }
+        if unreadable > 0 {
+            println!("{} has {} unreadable records; it won't be compacted \
+                      until they are fixed or removed", path, unreadable);
+        }
+
+        let (writes, writes_rx) = channel();
+        let path = String::from(path);
+        thread::spawn(move || write_log(path, log, writes_rx));
+
+        let live_records = state.snapshot().len();
+        let mut storage = LogStorage {
+            writes: writes,
+            state: state,
+            log_records: log_records,
+            live_records: live_records,
+            unreadable: unreadable,
+        };
+        try!(storage.maybe_compact());
+        Ok(storage)
+    }
+
+    /// Applies a record to the in-memory state and queues it to be appended
+    /// to the log.
+    ///
+    /// `grows` says whether the record adds live state rather than replacing
+    /// or removing it.
+    fn append(&mut self, record: Record, grows: bool) -> Result<()> {
+        try!(self.send(LogWrite::Append(record.clone())));
+        self.state.apply(record);
+        self.log_records += 1;
+        if grows {
+            self.live_records += 1;
+        }
+        self.maybe_compact()
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.unreadable > 0
+            || self.log_records < COMPACT_MIN_RECORDS
+            || self.log_records < self.live_records * 2 {
+            return Ok(());
+        }
+
+        let records = self.state.snapshot();
+        self.log_records = records.len();
+        self.live_records = records.len();
+        self.send(LogWrite::Compact(records))
+    }
+
+    fn send(&self, write: LogWrite) -> Result<()> {
+        self.writes.send(write).map_err(|_| {
+            io::Error::new(ErrorKind::BrokenPipe, "the log writer has stopped")
+        })
+    }
+}
+
+/// Work for the thread writing the log, done in the order sent.
+enum LogWrite {
+    Append(Record),
+    /// rewrite the log to hold just these records
+    Compact(Vec<Record>),
+    /// make everything written so far durable, then reply
+    Sync(Sender<Result<()>>),
+}
+
+/// Writes the log at `path` until the `LogStorage` is dropped. Appends are
+/// buffered, and flushed whenever no more are waiting.
+fn write_log(path: String, log: File, writes: Receiver<LogWrite>) {
+    let mut log = BufWriter::new(log);
+    loop {
+        let write = match writes.try_recv() {
+            Ok(write) => write,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = log.flush() {
+                    println!("failed to write {}: {}", path, e);
+                }
+                match writes.recv() {
+                    Ok(write) => write,
+                    Err(_) => break,
+                }
+            },
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        match write {
+            LogWrite::Append(record) => {
+                let line = format!("{}\n",
+                                   serde_json::to_string(&record).unwrap());
+                if let Err(e) = log.write_all(line.as_bytes()) {
+                    println!("failed to write {}: {}", path, e);
+                }
+            },
+            LogWrite::Compact(records) => {
+                if let Err(e) = log.flush() {
+                    println!("failed to write {}: {}", path, e);
+                }
+                match compact(&path[..], &records) {
+                    Ok(compacted) => {
+                        log = BufWriter::new(compacted);
+                        println!("compacted {} to {} records", path,
+                                 records.len());
+                    },
+                    // the old log is still intact, so keep appending to it
+                    Err(e) => println!("failed to compact {}: {}", path, e),
+                }
+            },
+            LogWrite::Sync(reply) => {
+                let result = log.flush()
+                    .and_then(|_| log.get_ref().sync_data());
+                let _ = reply.send(result);
+            },
+        }
+    }
+    if let Err(e) = log.flush() {
+        println!("failed to write {}: {}", path, e);
+    }
+}
+
+/// Replaces the log at `path` with one holding just `records`, returning
+/// it opened for appending.
+fn compact(path: &str, records: &Vec<Record>) -> Result<File> {
+    let tmp_path = format!("{}.compact", path);
+    {
+        let mut tmp = BufWriter::new(try!(File::create(&tmp_path)));
+        for record in records.iter() {
+            let line = format!("{}\n", serde_json::to_string(record).unwrap());
+            try!(tmp.write_all(line.as_bytes()));
+        }
+        try!(tmp.flush());
+        try!(tmp.get_ref().sync_all());
+    }
+    try!(fs::rename(&tmp_path, path));
+    OpenOptions::new().append(true).open(path)
+}
+
+impl Storage for LogStorage {
+    fn append_message(&mut self, room: &str, msg: &ServerMessage) -> Result<()> {
+        let record = try!(message_record(room, msg));
+        self.append(record, true)
+    }
+
+    fn messages(&self, room: &str, before: Option<u64>, limit: usize)
+                -> Vec<ServerMessage> {
+        self.state.messages(room, before, limit)
+    }
+
+    fn last_message_id(&self) -> Option<u64> {
+        self.state.last_message_id()
+    }
+
+    fn message(&self, id: u64) -> Option<(String, ServerMessage)> {
+        self.state.message(id)
+    }
+
+    fn edit_message(&mut self, id: u64, edit: &MessageEdit) -> Result<()> {
+        self.append(Record::Edit(id, edit.clone()), true)
+    }
+
+    fn message_edits(&self, id: u64) -> Vec<MessageEdit> {
+        self.state.message_edits(id)
+    }
+
+    fn delete_message(&mut self, id: u64) -> Result<()> {
+        if self.state.message(id).is_some() {
+            // the message, its edits and its reactions are no longer live
+            self.live_records -= 1 + self.state.message_edits(id).len()
+                + self.state.reactions.get(&id).map_or(0, |r| r.len());
+        }
+        self.append(Record::MessageDeleted(id), false)
+    }
+
+    fn replies(&self, parent_id: u64) -> Vec<ServerMessage> {
+        self.state.replies(parent_id)
+    }
+
+    fn reply_count(&self, parent_id: u64) -> usize {
+        self.state.reply_count(parent_id)
+    }
+
+    fn add_reaction(&mut self, reaction: &StoredReaction) -> Result<()> {
+        let grows = !self.state.has_reaction(reaction);
+        self.append(Record::ReactionAdded(reaction.clone()), grows)
+    }
+
+    fn remove_reaction(&mut self, reaction: &StoredReaction) -> Result<()> {
+        if self.state.has_reaction(reaction) {
+            self.live_records -= 1;
+        }
+        self.append(Record::ReactionRemoved(reaction.clone()), false)
+    }
+
+    fn reactions(&self) -> Vec<StoredReaction> {
+        self.state.reactions()
+    }
+
+    fn reserve_ids(&mut self, below: u64) -> Result<()> {
+        let grows = self.state.reserved_ids() == 0;
+        try!(self.append(Record::IdsReserved(below), grows));
+        let (reply, synced) = channel();
+        try!(self.send(LogWrite::Sync(reply)));
+        match synced.recv() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(ErrorKind::BrokenPipe,
+                                         "the log writer has stopped")),
+        }
+    }
+
+    fn reserved_ids(&self) -> u64 {
+        self.state.reserved_ids()
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<()> {
+        let grows = self.state.account(&account.name[..]).is_none();
+        self.append(Record::Account(account.clone()), grows)
+    }
+
+    fn account(&self, name: &str) -> Option<Account> {
+        self.state.account(name)
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.state.accounts()
+    }
+
+    fn save_room(&mut self, room: &RoomRecord) -> Result<()> {
+        let grows = !self.state.rooms.contains_key(&room.name);
+        self.append(Record::Room(room.clone()), grows)
+    }
+
+    fn rooms(&self) -> Vec<RoomRecord> {
+        self.state.rooms()
+    }
+
+    fn add_ban(&mut self, ban: &Ban) -> Result<()> {
+        let key = username::key(&ban.name[..]);
+        let grows = !self.state.bans.contains_key(&key);
+        self.append(Record::Ban(ban.clone()), grows)
+    }
+
+    fn remove_ban(&mut self, name: &str) -> Result<()> {
+        if self.state.bans.contains_key(&username::key(name)) {
+            self.live_records -= 1;
+        }
+        self.append(Record::BanRemoved(String::from(name)), false)
+    }
+
+    fn bans(&self) -> Vec<Ban> {
+        self.state.bans()
+    }
+}
+
+/// Opens the log at `path`, or in-memory storage if no path is configured.
+pub fn open(path: Option<&str>) -> Result<Box<Storage>> {
+    match path {
+        Some(path) => {
+            let storage = try!(LogStorage::open(path));
+            Ok(Box::new(storage))
+        },
+        None => Ok(Box::new(MemoryStorage::new())),
+    }
+}
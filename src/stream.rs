@@ -0,0 +1,134 @@
+use std::io::{Read, Write, Result, ErrorKind};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{ServerConfig, ServerConnection};
+
+/// A client connection, either plaintext or TLS.
+///
+/// Clones share the underlying connection, so one thread can read while
+/// another writes, as with `TcpStream::try_clone()`.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TcpStream, Arc<Mutex<ServerConnection>>),
+}
+
+impl Stream {
+    pub fn plain(stream: TcpStream) -> Stream {
+        Stream::Plain(stream)
+    }
+
+    pub fn tls(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Stream> {
+        let conn = match ServerConnection::new(config) {
+            Ok(conn) => conn,
+            Err(e) => return Err(::std::io::Error::new(ErrorKind::Other, e)),
+        };
+        Ok(Stream::Tls(stream, Arc::new(Mutex::new(conn))))
+    }
+
+    pub fn try_clone(&self) -> Result<Stream> {
+        match *self {
+            Stream::Plain(ref s) => Ok(Stream::Plain(try!(s.try_clone()))),
+            Stream::Tls(ref s, ref session) => {
+                Ok(Stream::Tls(try!(s.try_clone()), session.clone()))
+            },
+        }
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref s) => s,
+            Stream::Tls(ref s, _) => s,
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.socket().shutdown(how)
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.socket().set_write_timeout(dur)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut sock, ref session) => {
+                tls_read(sock, session, buf)
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut sock, ref session) => {
+                let mut conn = session.lock().unwrap();
+                let written = try!(conn.writer().write(buf));
+                try!(write_tls(sock, &mut conn));
+                Ok(written)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut sock, ref session) => {
+                let mut conn = session.lock().unwrap();
+                try!(conn.writer().flush());
+                write_tls(sock, &mut conn)
+            },
+        }
+    }
+}
+
+/// Reads plaintext from a TLS session shared with a writer.
+///
+/// The session lock is never held while waiting on the socket: the reader
+/// waits for data to arrive unlocked, then locks only to take in what has
+/// already arrived.
+fn tls_read(sock: &mut TcpStream, session: &Mutex<ServerConnection>,
+            buf: &mut [u8]) -> Result<usize> {
+    loop {
+        {
+            let mut conn = session.lock().unwrap();
+            match conn.reader().read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+        }
+
+        // blocks until there's data or the socket closes; no lock is held
+        if try!(sock.peek(&mut [0u8; 1])) == 0 {
+            return Ok(0);
+        }
+
+        let mut conn = session.lock().unwrap();
+        // the socket has data, so this doesn't block
+        if try!(conn.read_tls(sock)) == 0 {
+            return Ok(0);
+        }
+        if let Err(e) = conn.process_new_packets() {
+            // let the peer know why, e.g. with a handshake alert
+            let _ = write_tls(sock, &mut conn);
+            return Err(::std::io::Error::new(ErrorKind::InvalidData, e));
+        }
+        // handshake replies and the like
+        try!(write_tls(sock, &mut conn));
+    }
+}
+
+/// Sends whatever TLS data the session has queued.
+fn write_tls(sock: &mut TcpStream, conn: &mut ServerConnection) -> Result<()> {
+    while conn.wants_write() {
+        try!(conn.write_tls(sock));
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use rustls::ServerConfig;
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls_pemfile;
+
+/// Builds a rustls server config whose certificate is reloaded from disk
+/// whenever the certificate or key file changes.
+pub fn server_config(cert_path: &str, key_path: &str)
+                     -> Result<Arc<ServerConfig>> {
+    let resolver = try!(ReloadingCertResolver::new(cert_path, key_path));
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    Ok(Arc::new(config))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+    let mut cert_reader = BufReader::new(try!(File::open(cert_path)));
+    let mut certs = Vec::new();
+    for cert in rustls_pemfile::certs(&mut cert_reader) {
+        certs.push(try!(cert));
+    }
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              format!("no certificates in {}", cert_path)));
+    }
+
+    let mut key_reader = BufReader::new(try!(File::open(key_path)));
+    let key = match try!(rustls_pemfile::private_key(&mut key_reader)) {
+        Some(key) => key,
+        None => return Err(Error::new(ErrorKind::InvalidData,
+                                      format!("no private key in {}", key_path))),
+    };
+    let signing_key = match any_supported_type(&key) {
+        Ok(signing_key) => signing_key,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData,
+                                        format!("{}: {}", key_path, e))),
+    };
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    cert_modified: Option<SystemTime>,
+    key_modified: Option<SystemTime>,
+    key: Arc<CertifiedKey>,
+}
+
+/// Serves the certificate at `cert_path`, checking file modification times on
+/// every handshake and swapping in the new certificate when they change.
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    loaded: Mutex<LoadedCert>,
+}
+
+impl ReloadingCertResolver {
+    fn new(cert_path: &str, key_path: &str) -> Result<ReloadingCertResolver> {
+        let key = try!(load_certified_key(cert_path, key_path));
+        let loaded = LoadedCert {
+            cert_modified: modified(cert_path),
+            key_modified: modified(key_path),
+            key: Arc::new(key),
+        };
+        Ok(ReloadingCertResolver {
+            cert_path: String::from(cert_path),
+            key_path: String::from(key_path),
+            loaded: Mutex::new(loaded),
+        })
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let cert_modified = modified(&self.cert_path[..]);
+        let key_modified = modified(&self.key_path[..]);
+
+        if cert_modified != loaded.cert_modified
+            || key_modified != loaded.key_modified {
+            // case: files changed on disk; keep serving the old certificate
+            // if the new one doesn't load (e.g. caught mid-rotation)
+            match load_certified_key(&self.cert_path[..], &self.key_path[..]) {
+                Ok(key) => {
+                    println!("reloaded TLS certificate {}", self.cert_path);
+                    loaded.key = Arc::new(key);
+                    loaded.cert_modified = cert_modified;
+                    loaded.key_modified = key_modified;
+                },
+                Err(e) => {
+                    println!("failed to reload TLS certificate: {}", e);
+                },
+            }
+        }
+
+        Some(loaded.key.clone())
+    }
+}
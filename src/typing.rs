@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+/// Least time between a client's typing announcements in one room, so that
+/// toggling quickly doesn't flood the room.
+static TYPING_THROTTLE_MS: u64 = 1000;
+
+struct TypingState {
+    typing: bool,
+    /// when the client last said it was typing
+    refreshed_at: u64,
+    /// when others were last told the client started typing
+    announced_at: u64,
+}
+
+/// Who is typing in which room.
+///
+/// Clients keep saying they're typing while they are; one which goes quiet
+/// for `timeout_ms` is taken to have stopped.
+pub struct TypingTracker {
+    states: HashMap<(String, i64), TypingState>,
+    timeout_ms: u64,
+}
+
+impl TypingTracker {
+    pub fn new(timeout_ms: u64) -> TypingTracker {
+        TypingTracker {
+            states: HashMap::new(),
+            timeout_ms: timeout_ms,
+        }
+    }
+
+    /// Notes that a client is typing in a room. Returns whether the room
+    /// should be told; refreshes and throttled restarts aren't announced.
+    pub fn start(&mut self, room: &str, client_id: i64, now: u64) -> bool {
+        let key = (String::from(room), client_id);
+        if let Some(state) = self.states.get_mut(&key) {
+            if state.typing {
+                state.refreshed_at = now;
+                return false;
+            }
+            if now.saturating_sub(state.announced_at) < TYPING_THROTTLE_MS {
+                return false;
+            }
+            state.typing = true;
+            state.refreshed_at = now;
+            state.announced_at = now;
+            return true;
+        }
+
+        self.states.insert(key, TypingState {
+            typing: true,
+            refreshed_at: now,
+            announced_at: now,
+        });
+        true
+    }
+
+    /// Notes that a client stopped typing in a room. Returns whether it was
+    /// typing, and so whether the room should be told.
+    pub fn stop(&mut self, room: &str, client_id: i64) -> bool {
+        match self.states.get_mut(&(String::from(room), client_id)) {
+            Some(state) if state.typing => {
+                state.typing = false;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Stops everyone who hasn't refreshed in time, returning the room and
+    /// client_id of each.
+    pub fn expire(&mut self, now: u64) -> Vec<(String, i64)> {
+        let mut expired = Vec::new();
+        for (key, state) in self.states.iter_mut() {
+            let idle = now.saturating_sub(state.refreshed_at);
+            if state.typing && idle >= self.timeout_ms {
+                state.typing = false;
+                expired.push(key.clone());
+            }
+        }
+        // idle entries are only kept for throttling
+        self.states.retain(|_, state| {
+            state.typing
+                || now.saturating_sub(state.announced_at) < TYPING_THROTTLE_MS
+        });
+        expired
+    }
+
+    /// Forgets a client that has left, without announcing anything.
+    pub fn forget_client(&mut self, client_id: i64) {
+        self.states.retain(|&(_, id), _| id != client_id);
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton;
+
+/// Longest username accepted, in characters after normalization.
+pub static MAX_LEN: usize = 32;
+
+/// Names nobody may take, as `@room` and `@here` mention groups of people.
+pub static RESERVED: &'static [&'static str] = &["room", "here"];
+
+/// Why a username was refused.
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    Empty,
+    TooLong,
+    InvalidCharacter(char),
+    /// mixes ASCII and non-ASCII letters, as lookalike names tend to
+    MixedScript,
+    Reserved(String),
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UsernameError::Empty => write!(f, "usernames can't be empty"),
+            UsernameError::TooLong => {
+                write!(f, "usernames can be at most {} characters", MAX_LEN)
+            },
+            UsernameError::InvalidCharacter(c) => {
+                write!(f, "usernames may only contain letters, digits, '_', \
+                           '-' and '.', not {:?}", c)
+            },
+            UsernameError::MixedScript => {
+                write!(f, "usernames can't mix Latin and non-Latin letters")
+            },
+            UsernameError::Reserved(ref name) => {
+                write!(f, "{} can't be a username; @{} mentions a group",
+                       name, name)
+            },
+        }
+    }
+}
+
+fn is_allowed(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+/// Validates a requested username, returning it in Unicode NFKC form.
+pub fn normalize(name: &str) -> Result<String, UsernameError> {
+    let name: String = name.nfkc().collect();
+
+    if name.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    if name.chars().count() > MAX_LEN {
+        return Err(UsernameError::TooLong);
+    }
+    if let Some(c) = name.chars().filter(|c| !is_allowed(*c)).next() {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+
+    let has_ascii = name.chars().any(|c| c.is_ascii_alphabetic());
+    let has_non_ascii = name.chars().any(|c| c.is_alphabetic() && !c.is_ascii());
+    if has_ascii && has_non_ascii {
+        return Err(UsernameError::MixedScript);
+    }
+    if let Some(reserved) = RESERVED.iter().find(|r| key(r) == key(&name)) {
+        return Err(UsernameError::Reserved(String::from(*reserved)));
+    }
+
+    Ok(name)
+}
+
+/// The form in which usernames are compared for uniqueness: NFKC, then
+/// case-folded, then reduced to its UTS #39 confusable skeleton, so `Alice`
+/// and `ａｌｉｃｅ` are the same name, and so are `ace` and the Cyrillic
+/// `асе`.
+pub fn key(name: &str) -> String {
+    let name: String = name.nfkc().collect();
+    skeleton(&name.to_lowercase()[..]).collect()
+}
+
+/// Names mentioned in a message as `@name`, in order and without repeats.
+///
+/// An `@` inside a word, as in an email address, isn't a mention, and a
+/// trailing '.' or '-' is taken as punctuation rather than part of the name.
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut prev: Option<char> = None;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let starts_mention = c == '@' && !prev.map_or(false, is_allowed);
+        prev = Some(c);
+        if !starts_mention {
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if !is_allowed(c) {
+                break;
+            }
+            name.push(c);
+            prev = Some(c);
+            chars.next();
+        }
+        let name = name.trim_right_matches(|c| c == '.' || c == '-');
+        if !name.is_empty() && !names.iter().any(|n| key(n) == key(name)) {
+            names.push(String::from(name));
+        }
+    }
+    names
+}
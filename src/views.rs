@@ -1,14 +1,17 @@
 // use std::old_io::timer::sleep;
 // use std::time::duration::Duration;
 // use std::str::from_utf8;
-use http::{Request, Header};
+use http::Request;
 
 use std::io::Write;
 use sha1::Sha1;
 use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json;
 
 use ws;
+use auth;
 use chat;
+use protocol::{self, Codec};
 
 static DOCUMENT: &'static str = include_str!("index.html");
 static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
@@ -27,6 +30,36 @@ pub fn index(request: Request) -> (String, u32) {
 }
 
 pub fn ws(mut request: Request) -> (String, u32) {
+    let identity = match auth::authenticate(&request) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("rejected websocket: {}", e);
+            return (String::from("Unauthorized"), 401);
+        },
+    };
+    // first subprotocol offered which names a codec; JSON if none do
+    let subprotocol = request.get_header("Sec-WebSocket-Protocol")
+        .and_then(|offered| {
+            offered.split(',')
+                .map(|name| String::from(name.trim()))
+                .filter(|name| Codec::from_subprotocol(&name[..]).is_some())
+                .next()
+        });
+    let codec = subprotocol.as_ref()
+        .and_then(|name| Codec::from_subprotocol(&name[..]))
+        .unwrap_or(Codec::Json);
+    let protocol_version = match request.get_query_param("protocol") {
+        Some(version) => match version.parse() {
+            Ok(version) if protocol::is_supported(version) => version,
+            _ => return (String::from("Unsupported protocol version"), 400),
+        },
+        None => request.config.default_protocol_version,
+    };
+    if !codec.supports(protocol_version) {
+        return (format!("{} needs protocol version {}", codec.subprotocol(),
+                        protocol::PROTOCOL_VERSION), 400);
+    }
+
     let ws_key = request.get_header("Sec-WebSocket-Key").unwrap();
     let accept_key = verify_key(&ws_key);
     let accept_key_header = format!("Sec-WebSocket-Accept: {}", accept_key);
@@ -37,8 +70,11 @@ pub fn ws(mut request: Request) -> (String, u32) {
     response.push("Upgrade: websocket");
     response.push("Connection: Upgrade");
     response.push(&accept_key_header[..]);
-    if request.headers.iter().filter(|h| {h.key == "Sec-WebSocket-Protocol" && h.value == "chat"}).collect::<Vec<&Header>>().len() == 1 {
-        response.push("Sec-WebSocket-Protocol: chat");
+    let protocol_header = subprotocol.map(|name| {
+        format!("Sec-WebSocket-Protocol: {}", name)
+    });
+    if let Some(ref header) = protocol_header {
+        response.push(&header[..]);
     }
     response.push("");
     response.push("");
@@ -49,11 +85,22 @@ pub fn ws(mut request: Request) -> (String, u32) {
     request.stream.write_all(&res_str[..].as_bytes());
     request.stream.flush();
 
-    chat::ChatClient::run(request);
+    let resume_token = request.get_query_param("resume");
+    chat::ChatClient::run(request, identity, resume_token, protocol_version,
+                          codec);
 
     (String::from("fin"), 200)
 }
 
+pub fn stats(request: Request) -> (String, u32) {
+    if let Err(e) = auth::authenticate(&request) {
+        println!("rejected stats request: {}", e);
+        return (String::from("Unauthorized"), 401);
+    }
+    let stats = request.chat_server.stats();
+    (json::encode(&stats).unwrap(), 200)
+}
+
 pub fn error_404(request: Request) -> (String, u32) {
     (String::from("Not Found"), 404)
 }
@@ -30,7 +30,8 @@ static OPCODE_MASK:      u16 = 0b0000111100000000;
 static MASK_MASK:        u16 = 0b0000000010000000;
 static PAYLOAD_LEN_MASK: u16 = 0b0000000001111111;
 
-pub fn read_stream<T: Read + Write>(stream: &mut BufStream<T>) -> Result<Vec<u8>> {
+/// Reads one frame, returning its opcode and unmasked payload.
+pub fn read_stream<T: Read + Write>(stream: &mut BufStream<T>) -> Result<(u8, Vec<u8>)> {
     let mut mask_key = [0u8; 4];
     let header = try!(stream.read_u16::<BigEndian>());
 
@@ -60,13 +61,33 @@ pub fn read_stream<T: Read + Write>(stream: &mut BufStream<T>) -> Result<Vec<u8>
         }
     }
 
-    Ok(data)
+    Ok((opcode, data))
 }
 
-pub fn write_stream<T: Read + Write>(stream: &mut BufStream<T>, data: &Vec<u8>) {
+pub fn write_stream<T: Read + Write>(stream: &mut BufStream<T>, data: &Vec<u8>) -> Result<()> {
+    write_frame(stream, 0b0001, data)   // text mode
+}
+
+pub fn write_binary<T: Read + Write>(stream: &mut BufStream<T>, data: &Vec<u8>) -> Result<()> {
+    write_frame(stream, 0b0010, data)   // binary mode
+}
+
+/// Writes a close frame carrying the given status code.
+pub fn write_close<T: Read + Write>(stream: &mut BufStream<T>, code: u16) -> Result<()> {
+    let mut data: Vec<u8> = Vec::new();
+    try!(data.write_u16::<BigEndian>(code));
+    write_frame(stream, 0b1000, &data)
+}
+
+/// Answers a ping, echoing its payload.
+pub fn write_pong<T: Read + Write>(stream: &mut BufStream<T>, data: &Vec<u8>) -> Result<()> {
+    write_frame(stream, 0b1010, data)
+}
+
+fn write_frame<T: Read + Write>(stream: &mut BufStream<T>, opcode: u16, data: &Vec<u8>) -> Result<()> {
     let mut header: u16 = 0b0;
     let fin = 0b1 << 15;        // FIN frame
-    let opcode = 0b0001 << 8;   // text mode
+    let opcode = opcode << 8;
     let mask = 0b0 << 7;        // no mask
 
     let payload_len = match data.len() {
@@ -78,20 +99,20 @@ pub fn write_stream<T: Read + Write>(stream: &mut BufStream<T>, data: &Vec<u8>)
     header = header | fin | opcode | mask | payload_len;
 
 //  let _ = stream.write_be_u16(header);
-    let _ = stream.write_u16::<BigEndian>(header);
+    try!(stream.write_u16::<BigEndian>(header));
 
     if data.len() > 125 && data.len() > (2 as usize).pow(16) {
         // case: 64-bit data length
         let data_len: u64 = data.len() as u64;
 //      let _ = stream.write_be_u64(data_len);
-        let _ = stream.write_u64::<BigEndian>(data_len);
+        try!(stream.write_u64::<BigEndian>(data_len));
     } else if data.len() > 125 {
         // case: 16-bit data length
         let data_len: u16 = data.len() as u16;
 //      let _ = stream.write_be_u16(data_len);
-        let _ = stream.write_u16::<BigEndian>(data_len);
+        try!(stream.write_u16::<BigEndian>(data_len));
     };
 
-    let _ = stream.write_all(&data[..]);
-    let _ = stream.flush();
+    try!(stream.write_all(&data[..]));
+    stream.flush()
 }